@@ -0,0 +1,153 @@
+//! Validation of internal links in built HTML.
+
+use snafu::ResultExt;
+
+use crate::error::*;
+
+/// A local link that doesn't resolve to a built file.
+#[derive(Debug)]
+pub struct BrokenLink {
+    /// The built HTML file containing the link.
+    pub source: std::path::PathBuf,
+    /// The link as it appears in the `href` or `src` attribute.
+    pub link: String,
+}
+
+/// Returns the path, relative to the build directory, that `link` in the page at
+/// `source` points to, or `None` if it isn't a local link.
+///
+/// `source` is relative to the build directory. Absolute links under `root_url`
+/// are local, like the ones [`crate::DefaultRenderer`] writes.
+fn local_target(
+    source: &std::path::Path,
+    link: &str,
+    root_url: &str,
+) -> Option<std::path::PathBuf> {
+    let root_url = root_url.trim_end_matches('/');
+    let under_root = (!root_url.is_empty())
+        .then(|| link.strip_prefix(root_url))
+        .flatten()
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', '#', '?']));
+    let link = match under_root {
+        Some(rest) => std::borrow::Cow::Owned(format!("/{}", rest.trim_start_matches('/'))),
+        None => std::borrow::Cow::Borrowed(link),
+    };
+    let is_external = link.is_empty()
+        || link.starts_with('#')
+        || link.starts_with("//")
+        || link
+            .split_once(':')
+            .map(|(scheme, _)| !scheme.contains('/'))
+            .unwrap_or_default();
+    if is_external {
+        return None;
+    }
+    let link = link.split(['#', '?']).next().unwrap_or_default();
+    let link = urlencoding::decode(link)
+        .map(|link| link.into_owned())
+        .unwrap_or_else(|_| link.to_owned());
+
+    let mut target = if let Some(absolute) = link.strip_prefix('/') {
+        std::path::PathBuf::from(absolute)
+    } else {
+        source
+            .parent()
+            .unwrap_or(std::path::Path::new(""))
+            .join(&link)
+    };
+    if target.as_os_str().is_empty() || link.ends_with('/') {
+        target = target.join("index.html");
+    }
+
+    let mut normalized = std::path::PathBuf::new();
+    for component in target.components() {
+        match component {
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            std::path::Component::Normal(c) => normalized.push(c),
+            _ => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// Find every local `href`/`src` in the HTML files of `build_directory` that
+/// doesn't resolve to a file on disk or one of the manifest's `destinations`.
+/// Links under `root_url` are checked too.
+pub fn find_broken_links(
+    build_directory: &std::path::Path,
+    destinations: &std::collections::BTreeSet<std::path::PathBuf>,
+    root_url: &str,
+) -> Result<Vec<BrokenLink>, Error> {
+    let attribute = regex::Regex::new(r#"(?:href|src)\s*=\s*["']([^"']*)["']"#).unwrap();
+    let mut broken = vec![];
+    for file in crate::get_files(build_directory)? {
+        if file.extension().map(|ext| ext != "html").unwrap_or(true) {
+            continue;
+        }
+        let source = file.strip_prefix(build_directory).unwrap().to_path_buf();
+        let html = std::fs::read(&file).context(ReadBuiltSnafu { path: &file })?;
+        let html = String::from_utf8_lossy(&html);
+        for capture in attribute.captures_iter(&html) {
+            let link = &capture[1];
+            let Some(target) = local_target(&source, link, root_url) else {
+                continue;
+            };
+            // Links may leave out the `.html` extension, see `--strip-extension`.
+            let html_target = target.with_extension("html");
+            let resolves = destinations.contains(&target)
+                || build_directory.join(&target).is_file()
+                || build_directory.join(&target).join("index.html").is_file()
+                || (target.extension().is_none()
+                    && (destinations.contains(&html_target)
+                        || build_directory.join(&html_target).is_file()));
+            if !resolves {
+                broken.push(BrokenLink {
+                    source: file.clone(),
+                    link: link.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(broken)
+}
+
+#[cfg(test)]
+mod test {
+    use super::local_target;
+
+    #[test]
+    fn link_targets() {
+        let source = std::path::Path::new("blog/post.html");
+        assert_eq!(None, local_target(source, "https://example.com/x.html", ""));
+        assert_eq!(None, local_target(source, "mailto:me@example.com", ""));
+        assert_eq!(None, local_target(source, "#heading", ""));
+        assert_eq!(
+            Some("blog/img/a b.png".into()),
+            local_target(source, "img/a%20b.png?v=1", "")
+        );
+        assert_eq!(
+            Some("style.css".into()),
+            local_target(source, "../style.css", "")
+        );
+        assert_eq!(
+            Some("about/index.html".into()),
+            local_target(source, "/about/", "")
+        );
+
+        let root_url = "https://example.com/";
+        assert_eq!(
+            Some("about/index.html".into()),
+            local_target(source, "https://example.com/about/", root_url)
+        );
+        assert_eq!(
+            Some("index.html".into()),
+            local_target(source, "https://example.com", root_url)
+        );
+        assert_eq!(
+            None,
+            local_target(source, "https://example.community/", root_url)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Compression of text files when uploading, enabled with `--compress`.
+//!
+//! An s3 object has a single `Content-Encoding`, and cloudfront doesn't choose
+//! between objects by `Accept-Encoding`, so each file is uploaded under its key
+//! already compressed. Every browser accepts gzip. Brotli is accepted by modern
+//! browsers over https. With `both`, the key holds the gzip encoding and a
+//! brotli copy is uploaded under "{key}.br", for a cloudfront function that
+//! rewrites the request uri when the viewer accepts `br`.
+use std::io::Write;
+
+/// The file extension and key suffix of brotli copies uploaded with `both`.
+pub const BROTLI_SUFFIX: &str = ".br";
+
+/// How text files are compressed when uploading.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Upload gzip encoded files.
+    Gzip,
+    /// Upload brotli encoded files.
+    Br,
+    /// Upload gzip encoded files, with a brotli encoded copy at "{key}.br".
+    Both,
+}
+
+/// A compressed encoding of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` of files in this encoding.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Compress `bytes` in this encoding.
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+            Encoding::Brotli => {
+                let mut encoded = vec![];
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 11, 22);
+                    encoder.write_all(bytes).unwrap();
+                }
+                encoded
+            }
+        }
+    }
+}
+
+impl Compression {
+    /// The encodings to upload, each with the suffix added to the key.
+    pub fn encodings(self) -> &'static [(Encoding, &'static str)] {
+        match self {
+            Compression::Gzip => &[(Encoding::Gzip, "")],
+            Compression::Br => &[(Encoding::Brotli, "")],
+            Compression::Both => &[(Encoding::Gzip, ""), (Encoding::Brotli, BROTLI_SUFFIX)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let text = "<p>hello</p>\n".repeat(100);
+        let gzip = Encoding::Gzip.compress(text.as_bytes());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(gzip.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(text, decoded);
+
+        let brotli = Encoding::Brotli.compress(text.as_bytes());
+        assert!(brotli.len() < text.len());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(brotli.as_slice(), 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(text, decoded);
+    }
+}
@@ -0,0 +1,203 @@
+//! Loading a [`SiteConfig`] from a `pusha.toml` file.
+//!
+//! ```toml
+//! [environments.local]
+//! root_url = "http://localhost:8888"
+//!
+//! [environments.production]
+//! root_url = "https://example.com"
+//! s3_bucket = "example.com"
+//! # Or a list, like ["E1234567890", "E0987654321"], to invalidate several.
+//! cloudfront_distro = "E1234567890"
+//! region = "us-east-1"
+//! layout = "templates/layout.html"
+//! cache_control = "max-age=31536000"
+//! html_cache_control = "max-age=300"
+//!
+//! [environments.production.object_tags]
+//! managed-by = "pusha"
+//! commit = "${GIT_SHA}"
+//! ```
+use std::collections::BTreeMap;
+
+use snafu::ResultExt;
+
+use crate::{error::*, Environment, SiteConfig};
+
+/// The configuration of one environment in `pusha.toml`.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct EnvironmentConfig {
+    /// The URL the site is served from.
+    pub root_url: String,
+    /// The s3 bucket to deploy to.
+    pub s3_bucket: Option<String>,
+    /// The cloudfront distributions to invalidate, either one id or a list.
+    #[serde(
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub cloudfront_distro: Vec<String>,
+    /// The AWS region of the bucket and distribution.
+    pub region: Option<String>,
+    /// The HTML layout rendered pages are wrapped in.
+    pub layout: Option<std::path::PathBuf>,
+    /// The `Cache-Control` header of uploaded files.
+    pub cache_control: Option<String>,
+    /// The `Cache-Control` header of uploaded HTML files, instead of `cache_control`.
+    pub html_cache_control: Option<String>,
+    /// The contents of the generated `robots.txt`.
+    pub robots_txt: Option<String>,
+    /// The s3 storage class of uploaded objects, like "STANDARD_IA".
+    pub storage_class: Option<String>,
+    /// The tags of uploaded objects.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub object_tags: BTreeMap<String, String>,
+}
+
+/// Deserialize either one string or a list of them.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(
+        match <OneOrMany as serde::Deserialize>::deserialize(deserializer)? {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        },
+    )
+}
+
+/// The contents of a `pusha.toml` file.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct FileConfig {
+    /// Configuration keyed by environment name.
+    pub environments: BTreeMap<String, EnvironmentConfig>,
+}
+
+impl FileConfig {
+    /// Read the config from a TOML file.
+    pub fn read(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        log::info!("reading site config from '{}'", path.display());
+        let contents = std::fs::read_to_string(path).context(ReadConfigSnafu { path })?;
+        toml::from_str(&contents).context(ParseConfigSnafu { path })
+    }
+
+    fn get(&self, environment: &Environment) -> Option<&EnvironmentConfig> {
+        self.environments.get(&environment.to_string())
+    }
+}
+
+impl From<FileConfig> for SiteConfig {
+    fn from(config: FileConfig) -> Self {
+        let root_url = config.clone();
+        let cloudfront_distro = config.clone();
+        let s3_bucket = config.clone();
+        let region = config.clone();
+        let layout = config.clone();
+        let cache_control = config.clone();
+        let robots_txt = config.clone();
+        let storage_class = config.clone();
+        SiteConfig {
+            root_url: Box::new(move |env| {
+                root_url
+                    .get(env)
+                    .map(|c| c.root_url.clone())
+                    .unwrap_or_default()
+            }),
+            cloudfront_distro: Box::new(move |env| {
+                cloudfront_distro
+                    .get(env)
+                    .map(|c| c.cloudfront_distro.clone())
+                    .unwrap_or_default()
+            }),
+            s3_bucket: Box::new(move |env| s3_bucket.get(env).and_then(|c| c.s3_bucket.clone())),
+            region: Box::new(move |env| region.get(env).and_then(|c| c.region.clone())),
+            layout: Box::new(move |env| layout.get(env).and_then(|c| c.layout.clone())),
+            cache_control: Box::new(move |env, key| {
+                let c = cache_control.get(env)?;
+                if key.ends_with(".html") {
+                    c.html_cache_control
+                        .clone()
+                        .or_else(|| c.cache_control.clone())
+                } else {
+                    c.cache_control.clone()
+                }
+            }),
+            robots_txt: Box::new(move |env| robots_txt.get(env).and_then(|c| c.robots_txt.clone())),
+            storage_class: Box::new(move |env| {
+                storage_class.get(env).and_then(|c| c.storage_class.clone())
+            }),
+            object_tags: Box::new(move |env| {
+                config
+                    .get(env)
+                    .map(|c| c.object_tags.clone())
+                    .unwrap_or_default()
+            }),
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Read the site config from a `pusha.toml` file.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        FileConfig::read(path).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_config() {
+        let config: FileConfig = toml::from_str(
+            r#"
+            [environments.local]
+            root_url = "http://localhost:8888"
+
+            [environments.pr-1]
+            root_url = "https://pr-1.example.com"
+            s3_bucket = "pr-1.example.com"
+            cloudfront_distro = "E1234567890"
+            region = "us-east-1"
+
+            [environments.production]
+            root_url = "https://example.com"
+            cloudfront_distro = ["E1234567890", "E0987654321"]
+
+            [environments.pr-1.object_tags]
+            managed-by = "pusha"
+            "#,
+        )
+        .unwrap();
+        let cfg = SiteConfig::from(config);
+        let pr = Environment::Custom("pr-1".to_owned());
+        assert_eq!("http://localhost:8888", (cfg.root_url)(&Environment::Local));
+        assert_eq!(None, (cfg.s3_bucket)(&Environment::Local));
+        assert_eq!(Some("pr-1.example.com".to_owned()), (cfg.s3_bucket)(&pr));
+        assert_eq!(Some("us-east-1".to_owned()), (cfg.region)(&pr));
+        assert!((cfg.cloudfront_distro)(&Environment::Local).is_empty());
+        assert_eq!(vec!["E1234567890"], (cfg.cloudfront_distro)(&pr));
+        assert_eq!(2, (cfg.cloudfront_distro)(&Environment::Production).len());
+        assert_eq!("pusha", (cfg.object_tags)(&pr)["managed-by"]);
+        assert!((cfg.object_tags)(&Environment::Local).is_empty());
+
+        let path = std::env::temp_dir().join(format!("pusha-config-{}.toml", std::process::id()));
+        assert!(matches!(
+            FileConfig::read(&path),
+            Err(Error::ReadConfig { .. })
+        ));
+        std::fs::write(&path, "[environments.local\n").unwrap();
+        assert!(matches!(
+            FileConfig::read(&path),
+            Err(Error::ParseConfig { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! Errors returned by pusha.
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("'{}' does not exist, or is not a directory", path.display()))]
+    NotADirectory { path: std::path::PathBuf },
+
+    #[snafu(display("could not read directory '{}': {source}", path.display()))]
+    ReadDir {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("external page '{page}' is not text ({content_type})"))]
+    NotText { page: String, content_type: String },
+
+    #[snafu(display("could not read external pages from '{}': {source}", path.display()))]
+    ReadExternalPages {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not parse external pages in '{}': {source}", path.display()))]
+    ParseExternalPages {
+        path: std::path::PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[snafu(display("could not load environment file '{}': {source}", path.display()))]
+    EnvFile {
+        path: std::path::PathBuf,
+        source: dotenvy::Error,
+    },
+
+    #[snafu(display("could not fetch external page '{page}': {source}"))]
+    Fetch {
+        page: String,
+        source: reqwest::Error,
+    },
+
+    #[snafu(display("could not read external page '{}': {source}", path.display()))]
+    ReadPage {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("external page '{page}' responded with {status}"))]
+    RemoteStatus {
+        page: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[snafu(display("environment variable '{name}' used by '{page}' is not set"))]
+    EnvVar {
+        page: String,
+        name: String,
+        source: std::env::VarError,
+    },
+
+    #[snafu(display("invalid header '{name}' for '{page}'"))]
+    InvalidHeader { page: String, name: String },
+
+    #[snafu(display("'{}' already exists, not overwriting it", path.display()))]
+    InitExists { path: std::path::PathBuf },
+
+    #[snafu(display("could not write '{}': {source}", path.display()))]
+    WriteInit {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not read layout '{}': {source}", path.display()))]
+    ReadLayout {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("invalid glob '{pattern}': {source}"))]
+    Glob {
+        pattern: String,
+        source: globset::Error,
+    },
+
+    #[snafu(display("git error: {source}"))]
+    Git { source: git2::Error },
+
+    #[snafu(display(
+        "not confirmed to {action} '{environment}', pass --yes to skip confirmation"
+    ))]
+    NotConfirmed {
+        action: &'static str,
+        environment: crate::Environment,
+    },
+
+    #[snafu(display("there is no site manifest for '{environment}', run `build` first"))]
+    NoManifest { environment: crate::Environment },
+
+    #[snafu(display("the '{environment}' environment has no s3 bucket"))]
+    NoBucket { environment: crate::Environment },
+
+    #[snafu(display("the '{environment}' environment has no cloudfront distribution"))]
+    NoDistribution { environment: crate::Environment },
+
+    #[snafu(display(
+        "s3 {operation} of '{key}' failed: {}",
+        aws_sdk_s3::error::DisplayErrorContext(source.as_ref())
+    ))]
+    S3 {
+        operation: &'static str,
+        key: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("'{key}' was stored with {actual} bytes, expected {expected}"))]
+    VerifyLength {
+        key: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[snafu(display("'{key}' was not stored after uploading"))]
+    VerifyMissing { key: String },
+
+    #[snafu(display("'{key}' already exists and was not replaced"))]
+    AlreadyExists { key: String },
+
+    #[snafu(display(
+        "cloudfront invalidation failed: {}",
+        aws_sdk_s3::error::DisplayErrorContext(source.as_ref())
+    ))]
+    CloudFront {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("{count} built files are missing, build the site again"))]
+    MissingBuiltFiles { count: usize },
+
+    #[snafu(display("could not run the {stage} hook `{command}`: {source}"))]
+    RunHook {
+        stage: &'static str,
+        command: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("the {stage} hook `{command}` failed: {status}"))]
+    Hook {
+        stage: &'static str,
+        command: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[snafu(display("found {count} broken links"))]
+    BrokenLinks { count: usize },
+
+    #[snafu(display("could not parse manifest '{}': {source}", path.display()))]
+    ParseManifest {
+        path: std::path::PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("found {count} destinations built from more than one file"))]
+    Collisions { count: usize },
+
+    #[snafu(display(
+        "{} uploads failed:{}",
+        failures.len(),
+        failures
+            .iter()
+            .map(|(key, e)| format!("\n  '{key}': {e}"))
+            .collect::<String>()
+    ))]
+    Uploads { failures: Vec<(String, Error)> },
+
+    #[snafu(display("could not compress '{}': {source}", path.display()))]
+    Compress {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not render '{origin}': {message}"))]
+    RenderPage { origin: String, message: String },
+
+    #[snafu(display(
+        "{} pages failed to build:{}",
+        failures.len(),
+        failures
+            .iter()
+            .map(|e| format!("\n  {e}"))
+            .collect::<String>()
+    ))]
+    Builds { failures: Vec<Error> },
+
+    #[snafu(display("could not read stdin: {source}"))]
+    ReadStdin { source: std::io::Error },
+
+    #[snafu(display("could not render: {message}"))]
+    Render { message: String },
+
+    #[snafu(display("could not read manifest '{}': {source}", path.display()))]
+    ReadManifest {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not read manifest '{}' at '{git_ref}': {source}", path.display()))]
+    GitManifest {
+        path: std::path::PathBuf,
+        git_ref: String,
+        source: git2::Error,
+    },
+
+    #[snafu(display("cannot roll back '{environment}' to a manifest from '{previous}'"))]
+    RollbackEnvironment {
+        environment: crate::Environment,
+        previous: crate::Environment,
+    },
+
+    #[snafu(display(
+        "'{}' is the current manifest, give an older one or a --git-ref to roll back to",
+        path.display()
+    ))]
+    RollbackToCurrent { path: std::path::PathBuf },
+
+    #[snafu(display(
+        "{count} built files don't match the manifest being rolled back to, build \
+         that version of the site first"
+    ))]
+    RollbackBuild { count: usize },
+
+    #[snafu(display("found {count} problems in the manifest"))]
+    InvalidManifest { count: usize },
+
+    #[snafu(display(
+        "maintenance is already on, '{}' holds the previous website configuration",
+        path.display()
+    ))]
+    InMaintenance { path: std::path::PathBuf },
+
+    #[snafu(display(
+        "maintenance is not on, there is no stashed website configuration at '{}'",
+        path.display()
+    ))]
+    NotInMaintenance { path: std::path::PathBuf },
+
+    #[snafu(display("could not stash the website configuration in '{}': {source}", path.display()))]
+    StashWebsite {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not parse the stashed website configuration '{}': {source}", path.display()))]
+    ParseStash {
+        path: std::path::PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[snafu(display("cannot promote '{environment}' to itself"))]
+    PromoteToSelf { environment: crate::Environment },
+
+    #[snafu(display("could not write '{}': {source}", path.display()))]
+    WriteFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not read built file '{}': {source}", path.display()))]
+    ReadBuilt {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not read the site config '{}': {source}", path.display()))]
+    ReadConfig {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("could not parse the site config '{}': {source}", path.display()))]
+    ParseConfig {
+        path: std::path::PathBuf,
+        source: toml::de::Error,
+    },
+}
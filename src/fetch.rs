@@ -0,0 +1,169 @@
+//! Fetching of remote pages, limited in concurrency and rate.
+use std::{collections::HashMap, sync::Arc};
+
+/// How many times a rate limited (429) or timed out request is retried.
+const MAX_RETRIES: u32 = 5;
+
+/// An HTTP client that limits the number of in-flight requests, in total and
+/// optionally per host, and the rate at which requests are started, backing off
+/// when the server rate limits us.
+pub struct Fetcher {
+    client: reqwest::Client,
+    in_flight: tokio::sync::Semaphore,
+    per_host: Option<usize>,
+    hosts: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Semaphore>>>,
+    interval: Option<std::time::Duration>,
+    next_request: tokio::sync::Mutex<tokio::time::Instant>,
+}
+
+impl Fetcher {
+    /// Create a fetcher allowing at most `max_in_flight` concurrent requests and,
+    /// if given, at most `requests_per_second` requests per second.
+    pub fn new(max_in_flight: usize, requests_per_second: Option<f64>) -> Self {
+        Fetcher {
+            client: reqwest::Client::new(),
+            in_flight: tokio::sync::Semaphore::new(max_in_flight.max(1)),
+            per_host: None,
+            hosts: Default::default(),
+            interval: requests_per_second
+                .filter(|rps| *rps > 0.0)
+                .map(|rps| std::time::Duration::from_secs_f64(1.0 / rps)),
+            next_request: tokio::sync::Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Also allow at most `max_in_flight` concurrent requests to any one host, if given.
+    pub fn with_per_host(mut self, max_in_flight: Option<usize>) -> Self {
+        self.per_host = max_in_flight.map(|max| max.max(1));
+        self
+    }
+
+    /// Give up on each request after `timeout`, if given.
+    pub fn with_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        if let Some(timeout) = timeout {
+            self.client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+        }
+        self
+    }
+
+    /// Returns the semaphore limiting requests to the host of `url`, if there is
+    /// a per host limit.
+    fn host_limit(&self, url: &str) -> Option<Arc<tokio::sync::Semaphore>> {
+        let max_in_flight = self.per_host?;
+        let host = host(url)?;
+        let mut hosts = self.hosts.lock().unwrap();
+        let semaphore = hosts
+            .entry(host)
+            .or_insert_with(|| Arc::new(tokio::sync::Semaphore::new(max_in_flight)));
+        Some(semaphore.clone())
+    }
+
+    /// Wait until the rate limit allows another request to start.
+    async fn wait_turn(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let mut next_request = self.next_request.lock().await;
+        let now = tokio::time::Instant::now();
+        if *next_request > now {
+            tokio::time::sleep_until(*next_request).await;
+        }
+        *next_request = (*next_request).max(now) + interval;
+    }
+
+    /// GET the given URL with extra `headers`, retrying with backoff on
+    /// `429 Too Many Requests` and on timeouts.
+    pub async fn get(
+        &self,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> reqwest::Result<reqwest::Response> {
+        // The host's permit is taken first, so requests waiting on a busy host
+        // don't hold permits that requests to other hosts could use.
+        let host_limit = self.host_limit(url);
+        let _host_permit = match host_limit.as_ref() {
+            Some(semaphore) => Some(semaphore.acquire().await.unwrap()),
+            None => None,
+        };
+        let _permit = self.in_flight.acquire().await.unwrap();
+        let mut attempt = 0;
+        loop {
+            self.wait_turn().await;
+            let response = match self.client.get(url).headers(headers.clone()).send().await {
+                Err(e) if e.is_timeout() && attempt < MAX_RETRIES => {
+                    let delay = std::time::Duration::from_secs(1 << attempt);
+                    log::warn!("'{url}' timed out, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                response => response?,
+            };
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || attempt >= MAX_RETRIES
+            {
+                return Ok(response);
+            }
+            let delay = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(retry_after)
+                .unwrap_or(std::time::Duration::from_secs(1 << attempt));
+            log::warn!("'{url}' is rate limited, retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Returns the host and port of `url`, like "example.com:443".
+fn host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port_or_known_default().unwrap_or_default()
+    ))
+}
+
+/// Parse a `Retry-After` header value, either a number of seconds or an HTTP date.
+fn retry_after(value: &str) -> Option<std::time::Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(std::time::Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{host, retry_after};
+
+    #[test]
+    fn hosts() {
+        assert_eq!(
+            Some("example.com:443".to_owned()),
+            host("https://example.com/blog/")
+        );
+        assert_eq!(
+            Some("localhost:8888".to_owned()),
+            host("http://localhost:8888/")
+        );
+        assert_eq!(None, host("not a url"));
+    }
+
+    #[test]
+    fn retry_after_sanity() {
+        assert_eq!(
+            Some(std::time::Duration::from_secs(120)),
+            retry_after("120")
+        );
+        assert_eq!(None, retry_after("Wed, 21 Oct 2015 07:28:00 GMT"));
+        let later = chrono::Utc::now() + chrono::Duration::seconds(60);
+        let delay = retry_after(&later.to_rfc2822()).unwrap();
+        assert!(delay.as_secs() > 50 && delay.as_secs() <= 60);
+        assert_eq!(None, retry_after("soon"));
+    }
+}
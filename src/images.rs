@@ -0,0 +1,73 @@
+//! Generation of next-gen image variants for image assets.
+
+/// Returns whether WebP variants can be generated for the image at `path`.
+pub fn is_optimizable(path: &std::path::Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref(),
+        Some("png" | "jpg" | "jpeg")
+    )
+}
+
+/// Encode the image in `bytes` as WebP.
+///
+/// Returns `None` if the image can't be decoded or encoded, or if the WebP
+/// variant wouldn't be any smaller than the original.
+pub fn webp(path: &std::path::Path, bytes: &[u8]) -> Option<Vec<u8>> {
+    let img = match image::load_from_memory(bytes) {
+        Ok(img) => img,
+        Err(e) => {
+            log::warn!("could not decode image '{}': {e}", path.display());
+            return None;
+        }
+    };
+    // The WebP encoder only supports 8 bit RGB(A).
+    let img = image::DynamicImage::ImageRgba8(img.to_rgba8());
+    let mut output = std::io::Cursor::new(vec![]);
+    if let Err(e) = img.write_to(&mut output, image::ImageFormat::WebP) {
+        log::warn!("could not encode '{}' as webp: {e}", path.display());
+        return None;
+    }
+    let output = output.into_inner();
+    if output.len() >= bytes.len() {
+        log::debug!(
+            "skipping webp variant of '{}', it is not smaller than the original",
+            path.display()
+        );
+        return None;
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A PNG of a gradient, which WebP compresses better.
+    fn gradient_png() -> Vec<u8> {
+        let img =
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 128]));
+        let mut png = std::io::Cursor::new(vec![]);
+        img.write_to(&mut png, image::ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn webp_variants() {
+        let path = std::path::Path::new("photo.PNG");
+        assert!(is_optimizable(path));
+        assert!(is_optimizable(std::path::Path::new("photo.jpeg")));
+        assert!(!is_optimizable(std::path::Path::new("photo.gif")));
+
+        let png = gradient_png();
+        let variant = webp(path, &png).unwrap();
+        assert!(variant.len() < png.len());
+        assert_eq!(
+            image::ImageFormat::WebP,
+            image::guess_format(&variant).unwrap()
+        );
+        assert_eq!(None, webp(path, b"not an image"));
+    }
+}
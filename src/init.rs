@@ -0,0 +1,75 @@
+//! Scaffolding of a new pusha project.
+use snafu::ResultExt;
+
+use crate::error::*;
+
+/// The starting files of a new project, relative to its directory.
+const FILES: &[(&str, &str)] = &[
+    (
+        "content/index.md",
+        "# Hello\n\nThis page is built from `content/index.md`.\n",
+    ),
+    (
+        "pusha.toml",
+        r#"# Pass this to pusha with `--config pusha.toml`.
+[environments.local]
+root_url = "http://localhost:8888"
+
+# [environments.production]
+# root_url = "https://example.com"
+# s3_bucket = "example.com"
+# cloudfront_distro = "E1234567890"
+# region = "us-west-1"
+"#,
+    ),
+    (
+        crate::IGNORE_FILE,
+        "# Content files matching these globs are not built, one per line.\ncontent/drafts/**\n",
+    ),
+];
+
+/// Write the starting files of a new project into `dir`, returning their paths.
+///
+/// Nothing is written if any of the files already exists.
+pub fn init(dir: impl AsRef<std::path::Path>) -> Result<Vec<std::path::PathBuf>, Error> {
+    let dir = dir.as_ref();
+    let files = FILES
+        .iter()
+        .map(|(path, contents)| (dir.join(path), *contents))
+        .collect::<Vec<_>>();
+    if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return InitExistsSnafu { path }.fail();
+    }
+
+    for (path, contents) in files.iter() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(WriteInitSnafu { path })?;
+        }
+        std::fs::write(path, contents).context(WriteInitSnafu { path })?;
+        log::info!("created '{}'", path.display());
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn init_sanity() {
+        let dir = std::env::temp_dir().join(format!("pusha-init-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let files = init(&dir).unwrap();
+        assert_eq!(FILES.len(), files.len());
+        assert!(dir.join("content/index.md").is_file());
+        crate::config::FileConfig::read(dir.join("pusha.toml")).unwrap();
+
+        std::fs::write(dir.join("content/index.md"), "# Mine\n").unwrap();
+        assert!(matches!(init(&dir), Err(Error::InitExists { .. })));
+        assert_eq!(
+            "# Mine\n",
+            std::fs::read_to_string(dir.join("content/index.md")).unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,45 +1,213 @@
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque},
-    io::Read,
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Write},
     str::FromStr,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::Parser;
 
+pub use compress::Compression;
+pub use config::{EnvironmentConfig, FileConfig};
+pub use error::Error;
+use error::*;
+#[cfg(feature = "default-renderer")]
+pub use render::DefaultRenderer;
+use snafu::{OptionExt, ResultExt};
+pub use store::{
+    list_all_objects, CacheInvalidator, CloudFrontInvalidator, MemoryInvalidator, MemoryObject,
+    MemoryStore, Multipart, ObjectHead, ObjectStore, PutObject, RedirectAll, RoutingRule, S3Store,
+    Website,
+};
+
+mod check;
+mod compress;
+mod config;
+mod error;
+mod fetch;
+mod images;
+mod init;
+mod minify;
+mod preload;
+#[cfg(feature = "default-renderer")]
+mod render;
+mod search;
+mod store;
+
+// Only one command is ever parsed, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand)]
 enum Command {
     /// Deploy the site from the `site` directory.
-    Deploy,
+    Deploy {
+        #[clap(flatten)]
+        build: BuildOptions,
+        #[clap(flatten)]
+        options: DeployOptions,
+    },
     /// Build the site locally, compiling templates and content into the `site` directory.
-    Build,
+    Build {
+        #[clap(flatten)]
+        options: BuildOptions,
+    },
+    /// Show what a fresh build would change, compared to the current manifest.
+    /// The build hooks aren't run, and no timings or preload map are written.
+    Diff {
+        #[clap(flatten)]
+        options: BuildOptions,
+    },
     /// Clean the local site directory.
     Clean,
+    /// Create a minimal project in the current directory: a `content/index.md`, a
+    /// sample `pusha.toml` and a `.pushaignore`. Existing files are not overwritten.
+    Init,
     /// Upload an asset.
     Upload {
         /// Local path to the asset to upload.
         path: std::path::PathBuf,
         /// S3 key string. If omitted, a default will be used (something like "uploads/filename.extension")
         key: Option<String>,
+        /// Fail instead of replacing an object that already exists at the key.
+        #[clap(long)]
+        create_only: bool,
+        /// Check the stored object's size against the local file after uploading,
+        /// and log its content type and cache control.
+        #[clap(long)]
+        verify: bool,
+        #[clap(flatten)]
+        multipart: Multipart,
+        #[clap(flatten)]
+        aws: AwsOptions,
+    },
+    /// Check the built site for internal links that don't resolve to a built file.
+    Check,
+    /// Check that the manifest parses, that every built file exists, and that
+    /// destinations are unique, well-formed s3 keys.
+    Validate,
+    /// Roll back to a previous deploy by re-uploading the files referenced by an older
+    /// manifest and deleting anything that has been added since.
+    Rollback {
+        /// Path to the previous manifest. Defaults to "{environment}.yaml", the
+        /// current manifest, which then has to be read at an older `--git-ref`.
+        manifest: Option<std::path::PathBuf>,
+        /// Git ref to read the previous manifest from, instead of the working tree.
+        #[clap(long)]
+        git_ref: Option<String>,
+        #[clap(flatten)]
+        aws: AwsOptions,
+    },
+    /// Delete every object in the bucket, or every object under a prefix, and reset
+    /// the local manifest.
+    Purge {
+        /// Only delete objects whose keys start with this prefix.
+        #[clap(long)]
+        prefix: Option<String>,
+        /// Purge without asking for confirmation. Required for production.
+        #[clap(long, short = 'y')]
+        yes: bool,
+        #[clap(flatten)]
+        aws: AwsOptions,
+    },
+    /// Render markdown read from stdin with the configured renderer, writing the
+    /// HTML to stdout. No files or manifests are read or written.
+    Render {
+        /// Extra classes for the renderer to add to the content.
+        #[clap(long, default_value = "")]
+        extra_classes: String,
+        /// Ask the renderer to inject anchors into headings.
+        #[clap(long)]
+        heading_anchors: bool,
+        /// Ask the renderer to inject a table of contents.
+        #[clap(long)]
+        toc: bool,
+    },
+    /// Deploy the built files of one environment to another, so both serve the
+    /// same artifacts, and save them as the other environment's manifest.
+    Promote {
+        /// The environment whose manifest and built files are promoted.
+        from: Environment,
+        /// The environment to deploy them to.
+        to: Environment,
+        /// Replace the root url of `from` with the root url of `to` in text files,
+        /// writing the rewritten copies to "{build_directory}-{to}".
+        #[clap(long)]
+        rewrite_urls: bool,
+        /// Promote to production without asking for confirmation.
+        #[clap(long, short = 'y')]
+        yes: bool,
+        #[clap(flatten)]
+        aws: AwsOptions,
+    },
+    /// Serve a maintenance page in place of the site's directories and missing
+    /// pages, or stop serving it.
+    ///
+    /// Turning maintenance on uploads the page as "maintenance.html", makes it the
+    /// bucket's index and error document, without any redirects, and stashes the
+    /// previous website configuration in "{environment}.website.yaml". Pages
+    /// requested by their full key are still served. Turning it off restores
+    /// that configuration. Both invalidate "/*".
+    Maintenance {
+        #[clap(value_enum)]
+        mode: Maintenance,
+        /// Local path to the maintenance page, uploaded when turning maintenance on.
+        #[clap(long, default_value = MAINTENANCE_KEY)]
+        page: std::path::PathBuf,
+        /// Change production without asking for confirmation.
+        #[clap(long, short = 'y')]
+        yes: bool,
+        #[clap(flatten)]
+        aws: AwsOptions,
+    },
+    /// Print the configuration the environment resolves to, with the bucket,
+    /// distribution and storage class overrides applied, then exit. It is printed
+    /// as YAML, or as JSON with `--output-format json`.
+    Config {
+        #[clap(flatten)]
+        aws: AwsOptions,
     },
 }
 
-#[derive(
-    Clone,
-    Copy,
-    Default,
-    Debug,
-    ValueEnum,
-    PartialEq,
-    Eq,
-    Hash,
-    serde::Serialize,
-    serde::Deserialize,
-)]
+/// Whether the site serves a maintenance page.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Maintenance {
+    /// Serve the maintenance page for directories and missing pages.
+    On,
+    /// Serve the site again.
+    Off,
+}
+
+impl Command {
+    /// The name of the command, as given on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Deploy { .. } => "deploy",
+            Command::Build { .. } => "build",
+            Command::Diff { .. } => "diff",
+            Command::Clean => "clean",
+            Command::Init => "init",
+            Command::Upload { .. } => "upload",
+            Command::Check => "check",
+            Command::Validate => "validate",
+            Command::Rollback { .. } => "rollback",
+            Command::Purge { .. } => "purge",
+            Command::Render { .. } => "render",
+            Command::Promote { .. } => "promote",
+            Command::Maintenance { .. } => "maintenance",
+            Command::Config { .. } => "config",
+        }
+    }
+}
+
+/// A deployment environment.
+///
+/// Besides the well-known environments, any other name (for example a
+/// per-pull-request preview) can be used as a custom environment.
+#[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
 pub enum Environment {
     #[default]
     Local,
     Staging,
     Production,
+    Custom(String),
 }
 
 impl std::fmt::Display for Environment {
@@ -48,27 +216,527 @@ impl std::fmt::Display for Environment {
             Environment::Local => "local",
             Environment::Staging => "staging",
             Environment::Production => "production",
+            Environment::Custom(name) => name,
         })
     }
 }
 
+/// Error returned when parsing an invalid environment name.
+#[derive(Debug, snafu::Snafu)]
+#[snafu(display("unsupported environment '{name}'"))]
+pub struct EnvironmentParseError {
+    name: String,
+}
+
 impl FromStr for Environment {
-    type Err = snafu::Whatever;
+    type Err = EnvironmentParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        match s.to_ascii_lowercase().as_str() {
             "local" => Ok(Self::Local),
             "staging" => Ok(Self::Staging),
             "production" => Ok(Self::Production),
-            s => snafu::whatever!("unsupported environment '{s}'"),
+            _ if !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Ok(Self::Custom(s.to_owned()))
+            }
+            _ => EnvironmentParseSnafu { name: s }.fail(),
+        }
+    }
+}
+
+impl serde::Serialize for Environment {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Environment {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Files larger than this are not minified by default.
+const DEFAULT_MINIFY_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Images larger than this don't get WebP variants by default.
+const DEFAULT_IMAGE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Remote pages fetched at once by default.
+const DEFAULT_FETCH_CONCURRENCY: usize = 4;
+
+/// The AWS region used when neither the site config nor the environment sets one.
+const DEFAULT_REGION: &str = "us-west-1";
+
+/// The session name of an assumed AWS role, unless one is given.
+const DEFAULT_ROLE_SESSION_NAME: &str = "pusha";
+
+/// The extension of the file the website configuration is stashed in during
+/// maintenance, after the environment name.
+const WEBSITE_STASH_EXTENSION: &str = "website.yaml";
+
+/// The key the maintenance page is uploaded to.
+const MAINTENANCE_KEY: &str = "maintenance.html";
+
+/// The name of the deploy metadata written into the build, and its key in the
+/// manifest.
+const DEPLOY_INFO: &str = "deploy-info.json";
+
+/// The prefix of keys uploaded with the `upload` command, which aren't part of
+/// the built site.
+const UPLOADS_PREFIX: &str = "uploads/";
+
+/// The longest key s3 accepts, in bytes.
+const MAX_KEY_LENGTH: usize = 1024;
+
+/// The file listing globs of content files that are not built.
+const IGNORE_FILE: &str = ".pushaignore";
+
+/// The directory templates are loaded from by default.
+const DEFAULT_TEMPLATES_DIR: &str = "templates";
+
+/// The number of slowest pages logged after a build by default.
+const DEFAULT_SLOWEST: usize = 5;
+
+/// Options that control how the site is built.
+#[derive(clap::Args, Clone, Debug)]
+pub struct BuildOptions {
+    /// Minify HTML, CSS and JS outputs.
+    #[clap(long)]
+    pub minify: bool,
+
+    /// Files larger than this many bytes are not minified.
+    #[clap(long, default_value_t = DEFAULT_MINIFY_MAX_SIZE)]
+    pub minify_max_size: u64,
+
+    /// Generate WebP variants of PNG and JPEG assets alongside the originals, with
+    /// ".webp" appended to their names, like "photo.png.webp".
+    #[clap(long)]
+    pub optimize_images: bool,
+
+    /// Images larger than this many bytes don't get WebP variants.
+    #[clap(long, default_value_t = DEFAULT_IMAGE_MAX_SIZE)]
+    pub image_max_size: u64,
+
+    /// Render `page.md` to `page/index.html` instead of `page.html`, so it is
+    /// served at `/page/`.
+    #[clap(long)]
+    pub pretty_urls: bool,
+
+    /// Ask the renderer to inject anchors into headings.
+    #[clap(long)]
+    pub heading_anchors: bool,
+
+    /// Ask the renderer to inject a table of contents.
+    #[clap(long)]
+    pub toc: bool,
+
+    /// Maximum number of remote pages fetched at once.
+    #[clap(long, default_value_t = DEFAULT_FETCH_CONCURRENCY)]
+    pub fetch_concurrency: usize,
+
+    /// Maximum number of remote pages fetched at once from any one host, within
+    /// `--fetch-concurrency`. Unlimited by default.
+    #[clap(long)]
+    pub concurrency_per_host: Option<usize>,
+
+    /// Maximum number of remote page requests started per second.
+    #[clap(long)]
+    pub fetch_rate: Option<f64>,
+
+    /// Give up on a remote page request after this many seconds. Timed out
+    /// requests are retried a few times before `--on-remote-error` applies.
+    #[clap(long, value_name = "SECONDS")]
+    pub http_timeout: Option<u64>,
+
+    /// Only build content files matching this glob, like "content/blog/**". May be
+    /// repeated. The build directory is not cleaned, external pages are skipped,
+    /// and the manifest entries of other files are kept.
+    #[clap(long)]
+    pub include: Vec<String>,
+
+    /// Log this many of the slowest pages to render after building.
+    #[clap(long, default_value_t = DEFAULT_SLOWEST)]
+    pub slowest: usize,
+
+    /// Write the build duration and the render duration of every page to this
+    /// JSON file.
+    #[clap(long)]
+    pub timings: Option<std::path::PathBuf>,
+
+    /// Top-level content directories holding a localized variant of the site,
+    /// named by language like "en" and "fr". Pages under them know their language
+    /// and the URLs of their variants in the other languages.
+    #[clap(long = "language", value_delimiter = ',')]
+    pub languages: Vec<String>,
+
+    /// The path the site is served under, like "/docs", when it isn't served from
+    /// the root of the domain. Every destination, and so every s3 key and
+    /// invalidation path, is prefixed with it.
+    #[clap(long)]
+    pub base_path: Option<String>,
+
+    /// Drop the `.html` extension from local links in rendered pages, so they
+    /// read `/about` instead of `/about.html`, and `/blog/` instead of
+    /// `/blog/index.html`. The bucket or distribution must serve the right object.
+    #[clap(long)]
+    pub strip_extension: bool,
+
+    /// What to do when an external page can't be fetched or responds with an
+    /// error status.
+    #[clap(long, value_enum, default_value_t = OnRemoteError::Fail)]
+    pub on_remote_error: OnRemoteError,
+
+    /// Write a JSON search index of the rendered pages to "search-index.json",
+    /// for client-side search with `minisearch` or `lunr`. Pages with `draft: true`
+    /// in their front matter are left out.
+    #[clap(long)]
+    pub search_index: bool,
+
+    /// A directory to build the content under, so `content/blog/post.md` becomes
+    /// `{name}/blog/post.html`. Unlike `--base-path`, root-relative links in
+    /// pages are left alone. External pages are not affected.
+    #[clap(long, value_name = "NAME")]
+    pub keep_prefix: Option<String>,
+
+    /// The directory renderers load templates and partials from, given to them
+    /// as [`PageContext::templates_dir`].
+    #[clap(long, default_value = DEFAULT_TEMPLATES_DIR)]
+    pub templates_dir: std::path::PathBuf,
+
+    /// A shell command to run before building, like a CSS build that writes into
+    /// the content directory. The build fails if it does. `PUSHA_ENVIRONMENT` and
+    /// `PUSHA_BUILD_DIRECTORY` are set for it.
+    #[clap(long)]
+    pub pre_build_cmd: Option<String>,
+
+    /// A shell command to run after building, with the same environment as
+    /// `--pre-build-cmd`. The build fails if it does.
+    #[clap(long)]
+    pub post_build_cmd: Option<String>,
+
+    /// Don't empty the build directory first: built files are overwritten in
+    /// place, and files that weren't built are left alone. Files placed there by
+    /// hand aren't in the manifest, so they aren't deployed.
+    #[clap(long)]
+    pub no_clean: bool,
+
+    /// Warn instead of failing when files built from different sources have the
+    /// same destination, keeping whichever was built last.
+    #[clap(long)]
+    pub allow_collisions: bool,
+
+    /// Keep building when a page fails to render, skipping it, then fail with
+    /// every page that couldn't be built.
+    #[clap(long)]
+    pub keep_going: bool,
+
+    /// Replace `${NAME}` in rendered pages, after the layout is applied. The
+    /// built-in `GIT_SHA`, `GIT_SHORT_SHA`, `BUILD_TIME` and `PUSHA_ENVIRONMENT`
+    /// come first, then environment variables. Unknown names are left as they are.
+    #[clap(long)]
+    pub interpolate: bool,
+
+    /// Write a JSON map from the path of each page to the local stylesheets and
+    /// scripts it references, with a `Link` preload header value for each, to
+    /// this file. It is not deployed.
+    #[clap(long)]
+    pub preload_map: Option<std::path::PathBuf>,
+
+    /// The format to save the manifest in, set with the top-level
+    /// `--manifest-format` flag. Defaults to the format of the existing manifest.
+    #[clap(skip)]
+    pub manifest_format: Option<ManifestFormat>,
+}
+
+/// What to do with an external page that can't be fetched, or that responds
+/// with an error status like 404 or 500.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnRemoteError {
+    /// Fail the build.
+    #[default]
+    Fail,
+    /// Leave the page out of the build, with a warning.
+    Skip,
+    /// Reuse the page's previously built file, failing the build if there is none.
+    UseCached,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            minify: false,
+            minify_max_size: DEFAULT_MINIFY_MAX_SIZE,
+            optimize_images: false,
+            image_max_size: DEFAULT_IMAGE_MAX_SIZE,
+            pretty_urls: false,
+            heading_anchors: false,
+            toc: false,
+            fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+            concurrency_per_host: None,
+            fetch_rate: None,
+            http_timeout: None,
+            include: vec![],
+            slowest: DEFAULT_SLOWEST,
+            timings: None,
+            languages: vec![],
+            base_path: None,
+            strip_extension: false,
+            on_remote_error: OnRemoteError::Fail,
+            search_index: false,
+            keep_prefix: None,
+            templates_dir: DEFAULT_TEMPLATES_DIR.into(),
+            pre_build_cmd: None,
+            post_build_cmd: None,
+            no_clean: false,
+            allow_collisions: false,
+            keep_going: false,
+            interpolate: false,
+            preload_map: None,
+            manifest_format: None,
+        }
+    }
+}
+
+impl BuildOptions {
+    /// The render options set on the command line.
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            heading_anchors: self.heading_anchors,
+            toc: self.toc,
+        }
+    }
+
+    /// Returns the `base_path` relative to the root of the site, which is empty
+    /// when the site is served from the root.
+    fn base_path(&self) -> std::path::PathBuf {
+        std::path::PathBuf::from(
+            self.base_path
+                .as_deref()
+                .unwrap_or_default()
+                .trim_matches('/'),
+        )
+    }
+
+    /// Returns the directory content files are built under, relative to the root
+    /// of the site: the `base_path` followed by the `keep_prefix`.
+    fn content_path(&self) -> std::path::PathBuf {
+        self.base_path().join(
+            self.keep_prefix
+                .as_deref()
+                .unwrap_or_default()
+                .trim_matches('/'),
+        )
+    }
+
+    /// Returns the `base_path` as it appears in URLs, like "/docs", or an empty
+    /// string.
+    fn base_url_path(&self) -> String {
+        let base_path = to_s3_key(self.base_path());
+        if base_path.is_empty() {
+            base_path
+        } else {
+            format!("/{base_path}")
+        }
+    }
+
+    /// Returns a matcher for the `include` globs, or `None` if every file should be built.
+    fn include_matcher(&self) -> Result<Option<globset::GlobSet>, Error> {
+        glob_set(&self.include)
+    }
+
+    /// Post-process the bytes of a built file before they are written to `built_filepath`.
+    fn process(&self, built_filepath: &std::path::Path, bytes: Vec<u8>) -> Vec<u8> {
+        if self.minify {
+            minify::minify(built_filepath, bytes, self.minify_max_size)
+        } else {
+            bytes
+        }
+    }
+}
+
+/// Options that control how the site is deployed.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct DeployOptions {
+    /// Deploy to production, and delete orphaned objects from it, without asking
+    /// for confirmation.
+    #[clap(long, short = 'y')]
+    pub yes: bool,
+
+    /// Configure the bucket for static website hosting, with "index.html" as the
+    /// index document and "404.html" as the error document.
+    #[clap(long)]
+    pub configure_website: bool,
+
+    /// Only upload and invalidate content files that changed between this git ref
+    /// and `HEAD`. Everything is still built.
+    #[clap(long)]
+    pub since: Option<String>,
+
+    /// Deploy the files of the existing build instead of building the site again.
+    #[clap(long)]
+    pub no_build: bool,
+
+    /// Reconcile the bucket with the existing build instead of building the site
+    /// again: upload only the files whose content hash differs from the stored
+    /// object's, and delete objects that aren't in the manifest.
+    #[clap(long)]
+    pub manifest_only: bool,
+
+    /// Only upload and invalidate files of this kind.
+    #[clap(long, value_enum)]
+    pub only: Option<FileKind>,
+
+    /// Delete objects under the base path that aren't in the manifest, except
+    /// uploads. Production asks for confirmation unless `--yes` is given.
+    #[clap(long)]
+    pub delete_orphans: bool,
+
+    /// Log the orphaned objects `--delete-orphans` would delete, without deleting
+    /// them.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Identify the invalidation by the commit alone, instead of the commit and
+    /// the time. cloudfront returns the existing invalidation for a reference it
+    /// has seen, so re-deploying the same commit won't invalidate again: safe to
+    /// retry without paying twice, but a re-deploy of changed files at the same
+    /// commit stays cached.
+    #[clap(long)]
+    pub idempotent_invalidation: bool,
+
+    /// Upload text files like HTML, CSS and JS compressed, with their
+    /// `Content-Encoding` set. With `both`, gzip is uploaded to each key and
+    /// brotli to "{key}.br", for a cloudfront function to serve by
+    /// `Accept-Encoding`.
+    #[clap(long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Upload files whose keys match this glob, like "media/**", but leave them
+    /// out of the invalidation. May be repeated.
+    #[clap(long)]
+    pub exclude_from_invalidation: Vec<String>,
+
+    #[clap(flatten)]
+    pub multipart: Multipart,
+
+    #[clap(flatten)]
+    pub aws: AwsOptions,
+}
+
+/// Options that control how AWS is accessed.
+#[derive(clap::Args, Clone, Debug)]
+pub struct AwsOptions {
+    /// The AWS named profile to load credentials and settings from, instead of
+    /// the default credential chain.
+    #[clap(long)]
+    pub profile: Option<String>,
+
+    /// The ARN of an IAM role to assume for every AWS request, like one in the
+    /// account that owns the bucket. It is assumed with the credentials that
+    /// would be used otherwise.
+    #[clap(long, value_name = "ARN")]
+    pub assume_role_arn: Option<String>,
+
+    /// The external id the assumed role requires, if any.
+    #[clap(long, requires = "assume_role_arn")]
+    pub external_id: Option<String>,
+
+    /// The session name of the assumed role, which shows up in CloudTrail.
+    #[clap(long, requires = "assume_role_arn", default_value = DEFAULT_ROLE_SESSION_NAME)]
+    pub role_session_name: String,
+
+    /// The most AWS requests in flight at once, shared by uploads, deletes and
+    /// invalidations.
+    #[clap(long, default_value_t = store::DEFAULT_CONCURRENCY)]
+    pub concurrency: usize,
+
+    /// The s3 bucket to use instead of the site config's, set with the top-level
+    /// `--bucket` flag.
+    #[clap(skip)]
+    pub bucket: Option<String>,
+
+    /// The cloudfront distributions to use instead of the site config's, set with
+    /// the top-level `--distribution` flag.
+    #[clap(skip)]
+    pub distributions: Vec<String>,
+
+    /// The s3 storage class of uploaded objects, like "STANDARD_IA", instead of
+    /// the site config's. Objects are stored as "STANDARD" by default.
+    #[clap(long, value_parser = parse_storage_class)]
+    pub storage_class: Option<String>,
+
+    /// Give up on an attempt at an AWS request after this many seconds, so a
+    /// stalled connection fails instead of hanging. Timed out attempts are
+    /// retried by the SDK. Each part of a multipart upload is its own request.
+    #[clap(long, value_name = "SECONDS")]
+    pub aws_timeout: Option<u64>,
+}
+
+impl Default for AwsOptions {
+    fn default() -> Self {
+        AwsOptions {
+            profile: None,
+            assume_role_arn: None,
+            external_id: None,
+            role_session_name: DEFAULT_ROLE_SESSION_NAME.to_owned(),
+            concurrency: store::DEFAULT_CONCURRENCY,
+            bucket: None,
+            distributions: vec![],
+            storage_class: None,
+            aws_timeout: None,
         }
     }
 }
 
+impl AwsOptions {
+    /// The s3 bucket of `environment`, unless it is overridden.
+    pub fn bucket(&self, cfg: &SiteConfig, environment: &Environment) -> Option<String> {
+        self.bucket.clone().or_else(|| (cfg.s3_bucket)(environment))
+    }
+
+    /// The cloudfront distributions of `environment`, unless they are overridden.
+    pub fn distributions(&self, cfg: &SiteConfig, environment: &Environment) -> Vec<String> {
+        if self.distributions.is_empty() {
+            (cfg.cloudfront_distro)(environment)
+        } else {
+            self.distributions.clone()
+        }
+    }
+
+    /// The storage class of objects uploaded to `environment`, unless it is
+    /// overridden.
+    pub fn storage_class(&self, cfg: &SiteConfig, environment: &Environment) -> Option<String> {
+        self.storage_class
+            .clone()
+            .or_else(|| (cfg.storage_class)(environment))
+    }
+
+    /// Returns these options with the given bucket and distribution overrides.
+    fn with_overrides(mut self, bucket: Option<String>, distributions: Vec<String>) -> Self {
+        self.bucket = bucket.or(self.bucket);
+        if !distributions.is_empty() {
+            self.distributions = distributions;
+        }
+        self
+    }
+
+    /// Returns a semaphore limiting AWS requests to `concurrency`, to share between
+    /// a store and an invalidator.
+    fn in_flight(&self) -> std::sync::Arc<tokio::sync::Semaphore> {
+        std::sync::Arc::new(tokio::sync::Semaphore::new(self.concurrency.max(1)))
+    }
+}
+
 #[derive(Parser)]
 #[clap(author, version, about)]
 struct Cli {
-    /// The deployment environment. Should be "local", "staging" or "production".
+    /// The deployment environment. Either "local", "staging", "production" or the
+    /// name of a custom environment.
     #[clap(long, short = 'e', default_value = "local")]
     environment: Environment,
 
@@ -76,50 +744,348 @@ struct Cli {
     #[clap(long, short = 'b', default_value = "site")]
     build_directory: String,
 
+    /// Path to a dotenv file of `KEY=value` lines to load into the environment
+    /// before anything else reads it. Variables that are already set are kept.
+    #[clap(long)]
+    env_file: Option<std::path::PathBuf>,
+
+    /// Let the variables in `--env-file` replace ones that are already set.
+    #[clap(long, requires = "env_file")]
+    env_file_override: bool,
+
+    /// Path to a `pusha.toml` site config to use instead of the one given in code.
+    #[clap(long, env = "PUSHA_CONFIG")]
+    config: Option<std::path::PathBuf>,
+
+    /// The commit hash of the deployed source. If omitted, it is read from the
+    /// git repository in the current directory.
+    #[clap(long, env = "PUSHA_COMMIT")]
+    commit: Option<String>,
+
+    /// Path to a YAML or JSON list of external pages to build, in addition to the
+    /// ones given in code.
+    #[clap(long, env = "PUSHA_EXTERNAL_PAGES")]
+    external_pages: Option<std::path::PathBuf>,
+
+    /// How to print the site manifest to stdout after building or deploying.
+    #[clap(long, value_enum, default_value_t = OutputFormat::None)]
+    output_format: OutputFormat,
+
+    /// The format to save the site manifest in, as `{environment}.{extension}`.
+    /// Manifests are read in whichever format exists, preferring this one.
+    #[clap(long, value_enum)]
+    manifest_format: Option<ManifestFormat>,
+
+    /// The s3 bucket to use instead of the one in the site config.
+    #[clap(long, global = true, value_parser = parse_bucket)]
+    bucket: Option<String>,
+
+    /// The cloudfront distribution to use instead of the ones in the site config.
+    /// May be repeated to invalidate several.
+    #[clap(long, global = true)]
+    distribution: Vec<String>,
+
+    /// How to write log lines to stderr.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Exit with an error if anything logged a warning or an error, even if the
+    /// command succeeded, so degraded builds don't ship from CI.
+    #[clap(long, alias = "strict")]
+    fail_on_warning: bool,
+
     /// Subcommand
     #[clap(subcommand)]
     cmd: Command,
 }
 
-fn get_files(dir: impl AsRef<std::path::Path>) -> Vec<std::path::PathBuf> {
-    log::info!("reading directory '{}'", dir.as_ref().display());
-    if !(dir.as_ref().exists() && dir.as_ref().is_dir()) {
-        log::error!(
-            "'{}' does not exist, or is not a directory",
-            dir.as_ref().display()
-        );
-        panic!("not a dir");
+/// Parse an s3 storage class, like "STANDARD_IA".
+fn parse_storage_class(name: &str) -> Result<String, String> {
+    let classes = aws_sdk_s3::types::StorageClass::values();
+    if classes.contains(&name) {
+        Ok(name.to_owned())
+    } else {
+        Err(format!(
+            "'{name}' is not a storage class, expected one of {}",
+            classes.join(", ")
+        ))
+    }
+}
+
+/// Parse an s3 bucket name, checking it against s3's naming rules.
+fn parse_bucket(name: &str) -> Result<String, String> {
+    let is_valid = (3..=63).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && !name.contains("..");
+    if is_valid {
+        Ok(name.to_owned())
+    } else {
+        Err(format!(
+            "'{name}' is not a valid bucket name, it must be 3 to 63 lowercase letters, \
+             digits, dots or hyphens"
+        ))
+    }
+}
+
+/// Formats log lines can be written in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human readable text.
+    Text,
+    /// One JSON object per line, with the environment and command as fields.
+    Json,
+}
+
+impl LogFormat {
+    /// Initialize the logger, writing lines in this format.
+    fn init(self, environment: &Environment, command: &'static str) {
+        let mut builder = env_logger::builder();
+        if self == LogFormat::Json {
+            let environment = environment.to_string();
+            builder.format(move |buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": chrono::Utc::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                    "environment": environment,
+                    "command": command,
+                });
+                writeln!(buf, "{line}")
+            });
+        }
+        let logger = CountingLogger(builder.build());
+        // Warnings and errors are counted even when they aren't written.
+        log::set_max_level(logger.0.filter().max(log::LevelFilter::Warn));
+        log::set_boxed_logger(Box::new(logger)).unwrap();
+    }
+}
+
+/// The number of warnings and errors logged so far.
+static WARNINGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// A logger that counts warnings and errors in [`WARNINGS`] before passing
+/// records on.
+struct CountingLogger(env_logger::Logger);
+
+impl log::Log for CountingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn || self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= log::Level::Warn {
+            WARNINGS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        if self.0.matches(record) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Formats the site manifest, and other output, can be printed in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Yaml,
+    Json,
+    None,
+}
+
+impl OutputFormat {
+    /// Print `value` to stdout in this format.
+    fn print(self, value: &impl serde::Serialize) {
+        match self {
+            OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value).unwrap()),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+            OutputFormat::None => {}
+        }
     }
+}
+
+/// Returns every file under `dir`, recursively, sorted by path.
+fn get_files(dir: impl AsRef<std::path::Path>) -> Result<Vec<std::path::PathBuf>, Error> {
+    let dir = dir.as_ref();
+    log::info!("reading directory '{}'", dir.display());
+    snafu::ensure!(dir.is_dir(), NotADirectorySnafu { path: dir });
+
+    // Entries come in whatever order the OS gives, so they're sorted to keep builds
+    // reproducible.
+    let mut entries = std::fs::read_dir(dir)
+        .context(ReadDirSnafu { path: dir })?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadDirSnafu { path: dir })?;
+    entries.sort();
 
     let mut files = vec![];
-    for entry in std::fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
+    for path in entries {
         if path.is_file() {
             files.push(path);
         } else if path.is_dir() {
-            files.extend(get_files(path));
+            files.extend(get_files(path)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Returns a matcher for any of the `patterns`, or `None` if there are none.
+fn glob_set(patterns: &[String]) -> Result<Option<globset::GlobSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(globset::Glob::new(pattern).context(GlobSnafu { pattern })?);
+    }
+    let set = builder.build().context(GlobSnafu {
+        pattern: patterns.join(", "),
+    })?;
+    Ok(Some(set))
+}
+
+/// Returns a matcher for the globs listed in the `.pushaignore` file, or `None` if
+/// there is no such file. Blank lines and lines starting with `#` are skipped.
+fn ignore_matcher() -> Result<Option<globset::GlobSet>, Error> {
+    let Ok(contents) = std::fs::read_to_string(IGNORE_FILE) else {
+        return Ok(None);
+    };
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        builder.add(globset::Glob::new(pattern).context(GlobSnafu { pattern })?);
+    }
+    let set = builder.build().context(GlobSnafu {
+        pattern: IGNORE_FILE,
+    })?;
+    Ok(Some(set))
+}
+
+/// Returns the files in `content_dir`, merged with the files in the
+/// `{content_dir}.{environment}` overlay directory, if it exists.
+///
+/// Overlay files replace base files at the same relative path. Files named like
+/// the environment's manifest are left out, so its state is never deployed.
+fn get_content_files(
+    content_dir: impl AsRef<std::path::Path>,
+    environment: &Environment,
+) -> Result<Vec<std::path::PathBuf>, Error> {
+    let content_dir = content_dir.as_ref();
+    let mut files = BTreeMap::new();
+    for path in get_files(content_dir)? {
+        let relative = path.strip_prefix(content_dir).unwrap().to_path_buf();
+        files.insert(relative, path);
+    }
+
+    let overlay_dir = std::path::PathBuf::from(format!("{}.{environment}", content_dir.display()));
+    if overlay_dir.is_dir() {
+        log::info!("merging overlay directory '{}'", overlay_dir.display());
+        for path in get_files(&overlay_dir)? {
+            let relative = path.strip_prefix(&overlay_dir).unwrap().to_path_buf();
+            if let Some(base) = files.insert(relative, path.clone()) {
+                log::debug!("'{}' overrides '{}'", path.display(), base.display());
+            }
+        }
+    }
+    files.retain(|_, path| {
+        let is_manifest = is_manifest_file(&*path, environment);
+        if is_manifest {
+            log::warn!(
+                "'{}' is named like a manifest, not building it",
+                path.display()
+            );
+        }
+        !is_manifest
+    });
+    Ok(files.into_values().collect())
+}
+
+/// Returns whether `path` is named like the manifest of `environment`, in any
+/// format, or like its stashed website configuration.
+fn is_manifest_file(path: impl AsRef<std::path::Path>, environment: &Environment) -> bool {
+    let Some(name) = path.as_ref().file_name() else {
+        return false;
+    };
+    ManifestFormat::ALL
+        .iter()
+        .map(|format| format!("{environment}.{}", format.extension()))
+        .chain([format!("{environment}.{WEBSITE_STASH_EXTENSION}")])
+        .any(|manifest| name == manifest.as_str())
+}
+
+/// Returns the s3 key for `path`, with forward slashes, no leading slash and
+/// `.` and `..` components collapsed.
+fn to_s3_key(path: impl AsRef<std::path::Path>) -> String {
+    let path = path.as_ref().to_string_lossy().replace('\\', "/");
+    let mut components = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
         }
     }
-    files
+    components.join("/")
 }
 
+/// Returns `path` without its first directory, like the content directory, and
+/// optionally with a new extension.
+///
+/// Leading `./` components are ignored, and a path with no directory, like
+/// `index.md`, is kept as it is.
 fn pop_parent_replace_ext(
     path: impl AsRef<std::path::Path>,
     maybe_ext: Option<&str>,
 ) -> std::path::PathBuf {
-    let mut path = path.as_ref().to_path_buf();
-    let mut components = path.components().collect::<VecDeque<_>>();
-    if path.parent().is_some() {
-        let parent = components.pop_front().unwrap();
-        path = path.strip_prefix(parent).unwrap().to_path_buf();
+    let mut components = path
+        .as_ref()
+        .components()
+        .skip_while(|c| c == &std::path::Component::CurDir)
+        .collect::<VecDeque<_>>();
+    if components.len() > 1 {
+        components.pop_front();
     }
+    let mut path = components.into_iter().collect::<std::path::PathBuf>();
     if let Some(ext) = maybe_ext {
         path = path.with_extension(ext);
     }
     path
 }
 
+/// Returns the destination of a rendered markdown file.
+///
+/// `index.md` at any level becomes `index.html` in the same directory, which is
+/// what s3 website hosting serves for the directory's URL. With `pretty_urls`,
+/// `page.md` becomes `page/index.html` (except for the `404.md` error page),
+/// otherwise it becomes `page.html`.
+fn markdown_destination(
+    path: impl AsRef<std::path::Path>,
+    pretty_urls: bool,
+) -> std::path::PathBuf {
+    let destination = pop_parent_replace_ext(path, Some("html"));
+    let is_index = destination
+        .file_stem()
+        .map(|stem| stem == "index" || stem == "404")
+        .unwrap_or_default();
+    if pretty_urls && !is_index {
+        destination.with_extension("").join("index.html")
+    } else {
+        destination
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum PageSource {
     Remote(String),
@@ -133,72 +1099,1108 @@ impl PageSource {
             PageSource::Local(p) => p.to_str().unwrap(),
         }
     }
-}
-
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct ExternalPage {
-    /// URL source of the md file
-    pub source_url: PageSource,
-    /// Local path to host the resulting index.html
-    pub local_path: std::path::PathBuf,
-}
 
-/// Represents the statically configurable parts of the static site.
-pub struct SiteConfig {
-    /// A mapping of enviornment to URLs that tell the site where
-    /// to load things from and what the HREF of links should be.
-    pub root_url: fn(Environment) -> &'static str,
+    /// Read the content of the page, and when it was last modified.
+    ///
+    /// Remote pages are requested with the given extra `headers`, and with
+    /// `If-Modified-Since: modified_since`, if given. `None` is returned when the
+    /// server responds `304 Not Modified` to that, and never without it.
+    async fn read(
+        &self,
+        fetcher: &fetch::Fetcher,
+        headers: &BTreeMap<String, String>,
+        modified_since: Option<chrono::DateTime<chrono::FixedOffset>>,
+    ) -> Result<Option<(String, chrono::DateTime<chrono::FixedOffset>)>, Error> {
+        match self {
+            PageSource::Remote(url) => {
+                let mut headers = request_headers(url, headers)?;
+                if let Some(modified_since) = modified_since {
+                    let value = modified_since
+                        .with_timezone(&chrono::Utc)
+                        .format("%a, %d %b %Y %H:%M:%S GMT")
+                        .to_string();
+                    headers.insert(
+                        reqwest::header::IF_MODIFIED_SINCE,
+                        reqwest::header::HeaderValue::from_str(&value).unwrap(),
+                    );
+                }
+                let response = fetcher
+                    .get(url, headers)
+                    .await
+                    .context(FetchSnafu { page: url })?;
+                log::debug!("'{url}' headers: {:#?}", response.headers());
+                if response.status() == reqwest::StatusCode::NOT_MODIFIED
+                    && modified_since.is_some()
+                {
+                    return Ok(None);
+                }
+                snafu::ensure!(
+                    response.status().is_success(),
+                    RemoteStatusSnafu {
+                        page: url,
+                        status: response.status()
+                    }
+                );
+                let date = [reqwest::header::LAST_MODIFIED, reqwest::header::DATE]
+                    .into_iter()
+                    .find_map(|name| response.headers().get(name))
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+                // Links are relative to where the page ended up, after any redirects.
+                let base = response.url().clone();
+                let bytes = response
+                    .bytes()
+                    .await
+                    .context(FetchSnafu { page: url })?
+                    .to_vec();
+                let content = decode_page(url, content_type.as_deref(), bytes)?;
+                let content = absolute_links(&base, &content);
 
-    /// A mapping of environment to AWS cloudfront distributions.
-    pub cloudfront_distro: fn(Environment) -> Option<&'static str>,
+                let origin_modified = match date {
+                    None => {
+                        log::warn!("headers did not contain 'last-modified' or 'date'");
+                        chrono::Utc::now().fixed_offset()
+                    }
+                    Some(d) => {
+                        log::debug!("date: {d}");
+                        match chrono::DateTime::parse_from_rfc2822(&d) {
+                            Err(e) => {
+                                log::error!("could not parse date: {e}");
+                                chrono::Utc::now().fixed_offset()
+                            }
+                            Ok(d) => d,
+                        }
+                    }
+                };
+                Ok(Some((content, origin_modified)))
+            }
+            PageSource::Local(path) => {
+                let mut file = std::fs::File::open(path).context(ReadPageSnafu { path })?;
+                let modified = file
+                    .metadata()
+                    .and_then(|meta| meta.modified())
+                    .context(ReadPageSnafu { path })?;
+                let origin_modified =
+                    chrono::DateTime::<chrono::Utc>::from(modified).fixed_offset();
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)
+                    .context(ReadPageSnafu { path })?;
+                let content = decode_page(self.as_str(), None, bytes)?;
+                Ok(Some((content, origin_modified)))
+            }
+        }
+    }
+}
 
-    /// A mapping of environment to s3 bucket.
-    pub s3_bucket: fn(Environment) -> Option<&'static str>,
+/// Returns the given headers for a request to `page`, with `${NAME}` in their
+/// values replaced by the environment variable `NAME`.
+fn request_headers(
+    page: &str,
+    headers: &BTreeMap<String, String>,
+) -> Result<reqwest::header::HeaderMap, Error> {
+    let variable = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
+    let mut map = reqwest::header::HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let mut missing = None;
+        let value = variable.replace_all(value, |captures: &regex::Captures| {
+            std::env::var(&captures[1]).unwrap_or_else(|e| {
+                missing.get_or_insert((captures[1].to_owned(), e));
+                String::new()
+            })
+        });
+        if let Some((variable, source)) = missing {
+            return Err(source).context(EnvVarSnafu {
+                page,
+                name: variable,
+            });
+        }
+        let invalid = || InvalidHeaderSnafu { page, name }.build();
+        map.insert(
+            reqwest::header::HeaderName::from_str(name).map_err(|_| invalid())?,
+            reqwest::header::HeaderValue::from_str(&value).map_err(|_| invalid())?,
+        );
+    }
+    Ok(map)
 }
 
-pub trait Renderer {
-    type Error: std::error::Error;
+/// Rewrite relative link and image URLs in remote markdown or HTML `content` to
+/// absolute URLs, resolved against the page's URL `base`.
+///
+/// Absolute URLs, including protocol-relative ones, and anchors are left untouched.
+fn absolute_links(base: &reqwest::Url, content: &str) -> String {
+    let resolve = |url: &str| {
+        let is_absolute = url.is_empty()
+            || url.starts_with('#')
+            || url.starts_with("//")
+            || reqwest::Url::parse(url).is_ok();
+        if is_absolute {
+            return url.to_owned();
+        }
+        match base.join(url) {
+            Ok(resolved) => resolved.to_string(),
+            Err(e) => {
+                log::warn!("could not resolve link '{url}' against '{base}': {e}");
+                url.to_owned()
+            }
+        }
+    };
+    // Inline markdown links and images, reference definitions, and HTML attributes.
+    static PATTERNS: std::sync::LazyLock<[regex::Regex; 3]> = std::sync::LazyLock::new(|| {
+        [
+            r"(?P<prefix>\]\(\s*)(?P<url><[^>]*>|[^)\s]+)",
+            r"(?m)(?P<prefix>^[ ]{0,3}\[[^\]]+\]:[ \t]*)(?P<url><[^>]*>|\S+)",
+            r#"(?P<prefix>\b(?:href|src)\s*=\s*["'])(?P<url>[^"']*)"#,
+        ]
+        .map(|pattern| regex::Regex::new(pattern).unwrap())
+    });
+    let mut content = content.to_owned();
+    for pattern in PATTERNS.iter() {
+        content = pattern
+            .replace_all(&content, |captures: &regex::Captures| {
+                let url = &captures["url"];
+                let resolved = match url.strip_prefix('<').and_then(|url| url.strip_suffix('>')) {
+                    Some(url) => format!("<{}>", resolve(url)),
+                    None => resolve(url),
+                };
+                format!("{}{resolved}", &captures["prefix"])
+            })
+            .into_owned();
+    }
+    content
+}
+
+/// Decode the bytes of an external page as UTF-8.
+///
+/// Pages that aren't valid UTF-8 are decoded lossily, unless their content type
+/// or a NUL byte says they aren't text at all.
+fn decode_page(page: &str, content_type: Option<&str>, bytes: Vec<u8>) -> Result<String, Error> {
+    let is_text = content_type
+        .map(|ct| {
+            let essence = ct.split(';').next().unwrap_or_default().trim();
+            essence.starts_with("text/")
+                || matches!(
+                    essence,
+                    "application/json" | "application/xml" | "application/xhtml+xml"
+                )
+        })
+        .unwrap_or(true);
+    snafu::ensure!(
+        is_text && !bytes.contains(&0),
+        NotTextSnafu {
+            page,
+            content_type: content_type.unwrap_or("binary"),
+        }
+    );
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(content),
+        Err(e) => {
+            log::warn!("'{page}' is not valid utf-8, replacing invalid bytes: {e}");
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        }
+    }
+}
+
+/// The source of an external page, ready to be built.
+enum ExternalSource {
+    /// Freshly read content, and when it was last modified.
+    Read(String, chrono::DateTime<chrono::FixedOffset>),
+    /// The page hasn't been modified since the last build, so the previously
+    /// built bytes are reused, along with the hash of its content if known.
+    Unchanged(
+        PreviousBuild,
+        chrono::DateTime<chrono::FixedOffset>,
+        Option<String>,
+    ),
+    /// The page couldn't be fetched and is left out of the build.
+    Skipped,
+}
+
+/// The built bytes of an external page from the last build, and the extra
+/// renderer outputs built with it, keyed like the manifest.
+struct PreviousBuild {
+    bytes: Vec<u8>,
+    outputs: Vec<(String, ManifestFile, Vec<u8>)>,
+}
+
+impl PreviousBuild {
+    /// Read the previous build of the external page from `origin`, failing if
+    /// the page or any of its outputs can't be read.
+    fn read(files: &BTreeMap<String, ManifestFile>, origin: &str) -> Result<Self, std::io::Error> {
+        let page = files
+            .get(origin)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        let prefix = format!("{origin}#");
+        let outputs = files
+            .iter()
+            .filter(|(key, mf)| key.starts_with(&prefix) && mf.origin == origin)
+            .map(|(key, mf)| Ok((key.clone(), mf.clone(), std::fs::read(&mf.built_filepath)?)))
+            .collect::<Result<_, std::io::Error>>()?;
+        Ok(PreviousBuild {
+            bytes: std::fs::read(&page.built_filepath)?,
+            outputs,
+        })
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExternalPage {
+    /// URL source of the md file
+    pub source_url: PageSource,
+    /// Local path to host the resulting index.html
+    pub local_path: std::path::PathBuf,
+    /// Extra headers to request a remote source with, like `Authorization`.
+    /// `${NAME}` in a value is replaced by the environment variable `NAME`, so
+    /// secrets don't have to be committed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl ExternalPage {
+    /// Read a list of external pages from a YAML or JSON file, like:
+    ///
+    /// ```yaml
+    /// - source_url:
+    ///     Remote: https://example.com/devlog.md
+    ///   local_path: devlog/index.html
+    /// - source_url:
+    ///     Local: ../notes/README.md
+    ///   local_path: notes/index.html
+    /// - source_url:
+    ///     Remote: https://api.example.com/private/changelog.md
+    ///   local_path: changelog/index.html
+    ///   headers:
+    ///     Authorization: Bearer ${CHANGELOG_TOKEN}
+    /// ```
+    pub fn read_list(path: impl AsRef<std::path::Path>) -> Result<Vec<ExternalPage>, Error> {
+        let path = path.as_ref();
+        log::info!("reading external pages from '{}'", path.display());
+        let contents = std::fs::read_to_string(path).context(ReadExternalPagesSnafu { path })?;
+        serde_yaml::from_str(&contents).context(ParseExternalPagesSnafu { path })
+    }
+}
+
+/// A mapping from an environment to some configuration value.
+///
+/// These may capture state, so the config can be built at runtime.
+pub type EnvironmentFn<T> = Box<dyn Fn(&Environment) -> T + Send + Sync>;
+
+/// A mapping from an environment and the key of a deployed file to some
+/// configuration value.
+pub type PathFn<T> = Box<dyn Fn(&Environment, &str) -> T + Send + Sync>;
+
+/// Represents the configurable parts of the static site.
+///
+/// New fields may be added, so outside of this crate it is built with
+/// [`SiteConfig::builder`] or read with [`SiteConfig::from_file`].
+#[non_exhaustive]
+pub struct SiteConfig {
+    /// A mapping of enviornment to URLs that tell the site where
+    /// to load things from and what the HREF of links should be.
+    pub root_url: EnvironmentFn<String>,
+
+    /// A mapping of environment to AWS cloudfront distributions, like one per
+    /// domain in front of the same bucket. Each is invalidated after a deploy.
+    pub cloudfront_distro: EnvironmentFn<Vec<String>>,
+
+    /// A mapping of environment to s3 bucket.
+    pub s3_bucket: EnvironmentFn<Option<String>>,
+
+    /// A mapping of environment to AWS region. `None` uses the region from the
+    /// environment, like `AWS_REGION`, `AWS_DEFAULT_REGION` or the AWS profile,
+    /// falling back to "us-west-1".
+    pub region: EnvironmentFn<Option<String>>,
+
+    /// A mapping of environment to the path of an HTML layout that every rendered
+    /// page is wrapped in, like "templates/layout.html". See [`Layout`].
+    pub layout: EnvironmentFn<Option<std::path::PathBuf>>,
+
+    /// A mapping of environment and s3 key to the `Cache-Control` header the file
+    /// is served with, like "no-store" for staging and "max-age=31536000" for
+    /// production assets. `None` sends no header.
+    pub cache_control: PathFn<Option<String>>,
+
+    /// A mapping of environment to the contents of the generated `robots.txt`.
+    /// `None` allows everything in production and disallows everything elsewhere.
+    /// Ignored when the content has its own `robots.txt`.
+    pub robots_txt: EnvironmentFn<Option<String>>,
+
+    /// A mapping of environment to the s3 storage class of uploaded objects, like
+    /// "STANDARD_IA" for an archive. `None` stores them as "STANDARD".
+    pub storage_class: EnvironmentFn<Option<String>>,
+
+    /// A mapping of environment to the tags of uploaded objects, like
+    /// `managed-by=pusha`, for cost allocation and lifecycle rules. Values may use
+    /// the `--interpolate` variables, like "${GIT_SHA}". Empty sets no tags.
+    pub object_tags: EnvironmentFn<BTreeMap<String, String>>,
+}
+
+impl SiteConfig {
+    /// Returns a builder of a config with no bucket, distribution, layout or
+    /// `Cache-Control`, served from an empty root url so links stay root-relative.
+    ///
+    /// ```
+    /// let cfg = pusha::SiteConfig::builder()
+    ///     .root_url(|env| match env {
+    ///         pusha::Environment::Production => "https://example.com".to_owned(),
+    ///         _ => "http://localhost:8888".to_owned(),
+    ///     })
+    ///     .s3_bucket(|_| Some("example.com".to_owned()))
+    ///     .build();
+    /// assert!((cfg.cloudfront_distro)(&pusha::Environment::Production).is_empty());
+    /// ```
+    pub fn builder() -> SiteConfigBuilder {
+        SiteConfigBuilder::default()
+    }
+}
+
+/// Builds a [`SiteConfig`], starting from the defaults of [`SiteConfig::builder`].
+pub struct SiteConfigBuilder {
+    config: SiteConfig,
+}
+
+impl Default for SiteConfigBuilder {
+    fn default() -> Self {
+        SiteConfigBuilder {
+            config: SiteConfig {
+                root_url: Box::new(|_| String::new()),
+                cloudfront_distro: Box::new(|_| vec![]),
+                s3_bucket: Box::new(|_| None),
+                region: Box::new(|_| None),
+                layout: Box::new(|_| None),
+                cache_control: Box::new(|_, _| None),
+                robots_txt: Box::new(|_| None),
+                storage_class: Box::new(|_| None),
+                object_tags: Box::new(|_| BTreeMap::new()),
+            },
+        }
+    }
+}
+
+impl SiteConfigBuilder {
+    /// Set [`SiteConfig::root_url`].
+    pub fn root_url(mut self, f: impl Fn(&Environment) -> String + Send + Sync + 'static) -> Self {
+        self.config.root_url = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::cloudfront_distro`].
+    pub fn cloudfront_distro(
+        mut self,
+        f: impl Fn(&Environment) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.cloudfront_distro = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::s3_bucket`].
+    pub fn s3_bucket(
+        mut self,
+        f: impl Fn(&Environment) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.s3_bucket = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::region`].
+    pub fn region(
+        mut self,
+        f: impl Fn(&Environment) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.region = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::layout`].
+    pub fn layout(
+        mut self,
+        f: impl Fn(&Environment) -> Option<std::path::PathBuf> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.layout = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::cache_control`].
+    pub fn cache_control(
+        mut self,
+        f: impl Fn(&Environment, &str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.cache_control = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::robots_txt`].
+    pub fn robots_txt(
+        mut self,
+        f: impl Fn(&Environment) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.robots_txt = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::storage_class`].
+    pub fn storage_class(
+        mut self,
+        f: impl Fn(&Environment) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.storage_class = Box::new(f);
+        self
+    }
+
+    /// Set [`SiteConfig::object_tags`].
+    pub fn object_tags(
+        mut self,
+        f: impl Fn(&Environment) -> BTreeMap<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.object_tags = Box::new(f);
+        self
+    }
+
+    /// Returns the built config.
+    pub fn build(self) -> SiteConfig {
+        self.config
+    }
+}
+
+/// Common page features a renderer may support.
+///
+/// These are set with CLI flags and can be overridden per page in YAML front matter:
+///
+/// ```markdown
+/// ---
+/// toc: true
+/// ---
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Inject `id` anchors into headings.
+    pub heading_anchors: bool,
+    /// Inject a table of contents, along with the heading anchors it links to.
+    pub toc: bool,
+}
+
+impl RenderOptions {
+    /// Returns these options, overridden by any set in the front matter of `content`.
+    pub fn with_front_matter(self, content: &str) -> Self {
+        #[derive(serde::Deserialize)]
+        struct FrontMatter {
+            heading_anchors: Option<bool>,
+            toc: Option<bool>,
+        }
+
+        let front_matter = content
+            .strip_prefix("---\n")
+            .and_then(|rest| rest.split_once("\n---"))
+            .and_then(|(yaml, _)| serde_yaml::from_str::<FrontMatter>(yaml).ok());
+        match front_matter {
+            None => self,
+            Some(fm) => RenderOptions {
+                heading_anchors: fm.heading_anchors.unwrap_or(self.heading_anchors),
+                toc: fm.toc.unwrap_or(self.toc),
+            },
+        }
+    }
+}
+
+/// Everything a renderer knows about the page it is rendering.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PageContext<'a> {
+    /// The environment the site is being built for.
+    pub environment: &'a Environment,
+    /// Path of the built page, relative to the root of the site.
+    pub destination: &'a std::path::Path,
+    /// The live URL of the page.
+    pub url: String,
+    /// The page's slug, its file stem or the directory name for index pages.
+    pub slug: String,
+    /// Extra CSS classes for the page.
+    pub extra_classes: &'a str,
+    /// Page features requested of the renderer.
+    pub options: RenderOptions,
+    /// The page's language, if it is under one of the `--language` directories.
+    pub language: Option<&'a str>,
+    /// The same page in the other languages.
+    pub alternates: Vec<Alternate>,
+    /// The path the site is served under, like "/docs", or empty when it is served
+    /// from the root.
+    pub base_path: String,
+    /// The directory templates and partials are loaded from, like "templates".
+    pub templates_dir: &'a std::path::Path,
+}
+
+/// A localized variant of a page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alternate {
+    /// The language of the variant, like "fr".
+    pub language: String,
+    /// The live URL of the variant.
+    pub url: String,
+}
+
+impl<'a> PageContext<'a> {
+    fn new(
+        cfg: &SiteConfig,
+        environment: &'a Environment,
+        destination: &'a std::path::Path,
+        extra_classes: &'a str,
+        options: RenderOptions,
+    ) -> Self {
+        let stem = |path: &std::path::Path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let slug = match destination.parent() {
+            Some(parent) if stem(destination) == "index" => stem(parent),
+            _ => stem(destination),
+        };
+        PageContext {
+            environment,
+            destination,
+            url: page_url(&(cfg.root_url)(environment), destination),
+            slug,
+            extra_classes,
+            options,
+            language: None,
+            alternates: vec![],
+            base_path: String::new(),
+            templates_dir: std::path::Path::new(DEFAULT_TEMPLATES_DIR),
+        }
+    }
+}
+
+/// Returns the language of the page at `destination`, if it is in one of the
+/// `languages` directories, along with its path within that directory.
+fn page_language<'a>(
+    languages: &'a [String],
+    destination: &std::path::Path,
+) -> Option<(&'a str, std::path::PathBuf)> {
+    let mut components = destination.components();
+    let first = components.next()?.as_os_str();
+    let language = languages
+        .iter()
+        .find(|language| first == language.as_str())?;
+    Some((language.as_str(), components.as_path().to_path_buf()))
+}
+
+/// Returns the URL of the page at `destination`, with `index.html` pages
+/// addressed by their directory.
+fn page_url(root_url: &str, destination: &std::path::Path) -> String {
+    let mut path = destination
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if path == "index.html" {
+        path.clear();
+    } else if let Some(dir) = path.strip_suffix("/index.html") {
+        path = format!("{dir}/");
+    }
+    format!("{}/{path}", root_url.trim_end_matches('/'))
+}
+
+/// An HTML layout that rendered pages are wrapped in.
+///
+/// The layout contains `{{ name }}` placeholders, which are replaced with:
+/// * `content` - the rendered page
+/// * `environment` - the environment the site is built for
+/// * `root_url` - the root url of the environment
+/// * `url` - the live URL of the page
+/// * `slug` - the page's slug
+/// * `base_path` - the path the site is served under, or nothing
+/// * `language` - the page's language, or nothing
+/// * `alternates` - `<link rel="alternate" hreflang="...">` tags for the page in
+///   other languages
+///
+/// Other placeholders are left untouched.
+#[derive(Clone, Debug)]
+pub struct Layout(String);
+
+impl Layout {
+    /// Read a layout from an HTML file.
+    pub fn read(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        log::info!("reading layout '{}'", path.display());
+        let layout = std::fs::read_to_string(path).context(ReadLayoutSnafu { path })?;
+        Ok(Layout(layout))
+    }
+
+    /// Wrap the rendered `content` of `page` in this layout.
+    pub fn wrap(&self, cfg: &SiteConfig, page: &PageContext, content: &str) -> String {
+        static PLACEHOLDER: std::sync::LazyLock<regex::Regex> =
+            std::sync::LazyLock::new(|| regex::Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap());
+        PLACEHOLDER
+            .replace_all(&self.0, |captures: &regex::Captures| match &captures[1] {
+                "content" => content.to_owned(),
+                "environment" => page.environment.to_string(),
+                "root_url" => (cfg.root_url)(page.environment),
+                "url" => page.url.clone(),
+                "slug" => page.slug.clone(),
+                "base_path" => page.base_path.clone(),
+                "language" => page.language.unwrap_or_default().to_owned(),
+                "alternates" => page
+                    .alternates
+                    .iter()
+                    .map(|alternate| {
+                        format!(
+                            "<link rel=\"alternate\" hreflang=\"{}\" href=\"{}\">",
+                            alternate.language, alternate.url
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                _ => captures[0].to_owned(),
+            })
+            .into_owned()
+    }
+}
+
+pub trait Renderer {
+    type Error: std::error::Error;
 
     /// Interpolate a content string.
     fn render_content(
         cfg: &SiteConfig,
-        environment: Environment,
+        environment: &Environment,
         content: String,
         extra_classes: &str,
     ) -> Result<String, Self::Error>;
+
+    /// Interpolate a content string with the given [`RenderOptions`].
+    ///
+    /// By default this ignores the options and calls [`Renderer::render_content`].
+    fn render_content_with_options(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        content: String,
+        extra_classes: &str,
+        options: &RenderOptions,
+    ) -> Result<String, Self::Error> {
+        let _ = options;
+        Self::render_content(cfg, environment, content, extra_classes)
+    }
+
+    /// Render a page, with full knowledge of where it will live.
+    ///
+    /// By default this calls [`Renderer::render_content_with_options`].
+    fn render_page(
+        cfg: &SiteConfig,
+        page: &PageContext,
+        content: String,
+    ) -> Result<String, Self::Error> {
+        Self::render_content_with_options(
+            cfg,
+            page.environment,
+            content,
+            page.extra_classes,
+            &page.options,
+        )
+    }
+
+    /// Render any files to build alongside a page, like a JSON summary for a
+    /// search index or an AMP variant, from the page's unrendered content.
+    ///
+    /// Each output is a destination relative to the build directory, like the
+    /// page's own [`PageContext::destination`], and its bytes. Outputs are written
+    /// and recorded in the manifest with the page. By default there are none.
+    fn render_outputs(
+        cfg: &SiteConfig,
+        page: &PageContext,
+        content: &str,
+    ) -> Result<Vec<(std::path::PathBuf, Vec<u8>)>, Self::Error> {
+        let _ = (cfg, page, content);
+        Ok(vec![])
+    }
 }
 
+/// Returns the error of a page built from `origin` that failed to render.
+fn render_error(origin: &str, e: impl std::error::Error) -> Error {
+    Error::RenderPage {
+        origin: origin.to_owned(),
+        message: e.to_string(),
+    }
+}
+
+/// Pages that failed to build, collected with `--keep-going`.
+#[derive(Default)]
+struct BuildFailures(Vec<Error>);
+
+impl BuildFailures {
+    /// Record that a page failed to build, or return the error if the build
+    /// doesn't keep going.
+    fn fail(&mut self, options: &BuildOptions, e: Error) -> Result<(), Error> {
+        if !options.keep_going {
+            return Err(e);
+        }
+        log::error!("{e}, skipping it");
+        self.0.push(e);
+        Ok(())
+    }
+
+    /// Fail with every page that failed to build, if any.
+    fn finish(self) -> Result<(), Error> {
+        snafu::ensure!(self.0.is_empty(), BuildsSnafu { failures: self.0 });
+        Ok(())
+    }
+}
+
+/// How long a page took to render.
+#[derive(Debug, serde::Serialize)]
+struct PageTiming {
+    origin: String,
+    milliseconds: f64,
+    /// The local stylesheets and scripts the page references.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    assets: Vec<String>,
+}
+
+/// How long a build took, written with `--timings`.
+#[derive(Debug, serde::Serialize)]
+struct BuildTimings {
+    milliseconds: f64,
+    pages: Vec<PageTiming>,
+}
+
+impl BuildTimings {
+    /// Log the build duration and the `slowest` pages, and write the timings to
+    /// `path` if given.
+    fn report(mut self, slowest: usize, path: Option<&std::path::Path>) -> Result<(), Error> {
+        self.pages
+            .sort_by(|a, b| b.milliseconds.total_cmp(&a.milliseconds));
+        log::info!(
+            "built in {:.0}ms, rendering {} pages",
+            self.milliseconds,
+            self.pages.len()
+        );
+        for page in self.pages.iter().take(slowest) {
+            log::info!("  {:.1}ms {}", page.milliseconds, page.origin);
+        }
+        if let Some(path) = path {
+            log::info!("writing build timings to '{}'", path.display());
+            std::fs::write(path, serde_json::to_string_pretty(&self).unwrap())
+                .context(WriteFileSnafu { path })?;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata about a single deploy, written into the build as `deploy-info.json`
+/// and appended to the local `deploys.log` history.
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct DeployInfo {
+    /// Commit hash of the deployed source, if it could be determined.
+    pub commit: Option<String>,
+    /// When the deploy happened.
+    pub timestamp: chrono::DateTime<chrono::FixedOffset>,
+    /// The environment deployed to.
+    pub environment: Environment,
+    /// The user that ran the deploy, if known.
+    pub deployed_by: Option<String>,
+    /// Number of files in the deploy, not counting `deploy-info.json` itself.
+    pub file_count: usize,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct ManifestFile {
     origin: String,
     origin_modified: chrono::DateTime<chrono::FixedOffset>,
     built_filepath: std::path::PathBuf,
     destination: std::path::PathBuf,
+    /// The content type to serve the file with, recorded at build time. Manifests
+    /// written before this was recorded guess it from the built file when uploading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// What kind of file this is. Manifests written before this was recorded
+    /// infer it from the origin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kind: Option<FileKind>,
+    /// The hex encoded sha256 of the built file. Empty in manifests written
+    /// before this was recorded.
+    #[serde(default)]
+    content_hash: String,
+    /// The hex encoded sha256 of an external page's fetched content, so a page
+    /// that is fetched again unchanged isn't rendered again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_hash: Option<String>,
+}
+
+/// The kinds of files in a site.
+#[derive(
+    clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    /// A page rendered from markdown in the content directory.
+    Pages,
+    /// A file copied from the content directory, or generated alongside the site.
+    Assets,
+    /// A page rendered from an external source.
+    External,
+}
+
+impl ManifestFile {
+    /// The source the file was built from: a path in the content directory, the
+    /// URL of an external page, or the name of a generated file.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// When the source was last modified.
+    pub fn origin_modified(&self) -> chrono::DateTime<chrono::FixedOffset> {
+        self.origin_modified
+    }
+
+    /// Where the file was built, in the build directory.
+    pub fn built_filepath(&self) -> &std::path::Path {
+        &self.built_filepath
+    }
+
+    /// Where the file is deployed, relative to the root of the site.
+    pub fn destination(&self) -> &std::path::Path {
+        &self.destination
+    }
+
+    /// The content type the file is served with, if it was recorded.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The hex encoded sha256 of the built file, or empty if it wasn't recorded.
+    pub fn content_hash(&self) -> &str {
+        &self.content_hash
+    }
+
+    /// The live URL of the file in `environment`, with `index.html` pages
+    /// addressed by their directory.
+    pub fn url(&self, cfg: &SiteConfig, environment: &Environment) -> String {
+        page_url(&(cfg.root_url)(environment), &self.destination)
+    }
+
+    /// What kind of file this is.
+    pub fn kind(&self) -> FileKind {
+        self.kind.unwrap_or_else(|| {
+            if self.origin.starts_with("http://") || self.origin.starts_with("https://") {
+                FileKind::External
+            } else if self.origin.ends_with(".md") {
+                FileKind::Pages
+            } else {
+                FileKind::Assets
+            }
+        })
+    }
 }
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct SiteManifest {
     environment: Environment,
     build_directory: std::path::PathBuf,
+    /// A hash of what external pages were last rendered with besides their
+    /// content, like the layout and build options. See [`render_hash`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    render_hash: Option<String>,
     files: BTreeMap<String, ManifestFile>,
+    /// The format the manifest is saved in.
+    #[serde(skip)]
+    format: ManifestFormat,
+}
+
+/// Formats the site manifest can be saved in, as `{environment}.{extension}`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ManifestFormat {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ManifestFormat {
+    const ALL: [ManifestFormat; 3] = [
+        ManifestFormat::Yaml,
+        ManifestFormat::Json,
+        ManifestFormat::Toml,
+    ];
+
+    /// The file extension of manifests in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ManifestFormat::Yaml => "yaml",
+            ManifestFormat::Json => "json",
+            ManifestFormat::Toml => "toml",
+        }
+    }
+
+    /// The format of the manifest at `path`, by its extension, or yaml.
+    pub fn of(path: impl AsRef<std::path::Path>) -> Self {
+        let extension = path.as_ref().extension().unwrap_or_default();
+        Self::ALL
+            .into_iter()
+            .find(|format| extension == format.extension())
+            .unwrap_or_default()
+    }
+
+    /// The path of the manifest of `environment` in `format`. If there is no such
+    /// file, it's the path of an existing manifest in another format, so
+    /// switching formats keeps the manifest.
+    fn path(environment: &Environment, format: Option<Self>) -> std::path::PathBuf {
+        let path = |format: Self| format!("{environment}.{}", format.extension()).into();
+        format
+            .into_iter()
+            .chain(Self::ALL)
+            .map(path)
+            .find(|path: &std::path::PathBuf| path.exists())
+            .unwrap_or_else(|| path(format.unwrap_or_default()))
+    }
+
+    /// Parse a manifest in this format.
+    fn parse(
+        self,
+        contents: &[u8],
+    ) -> Result<SiteManifest, Box<dyn std::error::Error + Send + Sync>> {
+        let mut manifest: SiteManifest = match self {
+            ManifestFormat::Yaml => serde_yaml::from_slice(contents)?,
+            ManifestFormat::Json => serde_json::from_slice(contents)?,
+            ManifestFormat::Toml => toml::from_slice(contents)?,
+        };
+        manifest.format = self;
+        Ok(manifest)
+    }
+
+    /// Serialize a manifest in this format.
+    fn serialize(self, manifest: &SiteManifest) -> String {
+        match self {
+            ManifestFormat::Yaml => serde_yaml::to_string(manifest).unwrap(),
+            ManifestFormat::Json => serde_json::to_string_pretty(manifest).unwrap(),
+            ManifestFormat::Toml => toml::to_string_pretty(manifest).unwrap(),
+        }
+    }
+}
+
+/// The differences between two manifests, keyed by origin.
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestDiff {
+    /// Origins only in the newer manifest.
+    pub added: Vec<String>,
+    /// Origins only in the older manifest.
+    pub removed: Vec<String>,
+    /// Origins in both manifests that have changed.
+    pub modified: Vec<String>,
+}
+
+impl std::fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (label, keys) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("modified", &self.modified),
+        ] {
+            for key in keys {
+                writeln!(f, "{label:>8}: {key}")?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl SiteManifest {
-    fn new(environment: Environment, build_directory: std::path::PathBuf) -> Self {
-        let manifest_path = format!("{}.yaml", environment);
-        if let Ok(file) = std::fs::File::open(&manifest_path) {
-            log::info!("reading site manifest from {manifest_path}");
-            serde_yaml::from_reader(file).unwrap()
+    /// The environment this manifest is for.
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// The directory the site is built into.
+    pub fn build_directory(&self) -> &std::path::Path {
+        &self.build_directory
+    }
+
+    /// The built files, keyed by origin.
+    pub fn files(&self) -> &BTreeMap<String, ManifestFile> {
+        &self.files
+    }
+
+    /// Load the manifest of the given environment from `{environment}.yaml`, or
+    /// `{environment}.json` or `{environment}.toml`, or create an empty one if it
+    /// doesn't exist yet. Fails if the existing manifest can't be parsed.
+    pub fn new(
+        environment: Environment,
+        build_directory: std::path::PathBuf,
+    ) -> Result<Self, Error> {
+        Self::open(environment, build_directory, None)
+    }
+
+    /// Like [`SiteManifest::new`], preferring and saving in `format` if given.
+    pub fn open(
+        environment: Environment,
+        build_directory: std::path::PathBuf,
+        format: Option<ManifestFormat>,
+    ) -> Result<Self, Error> {
+        let manifest_path = ManifestFormat::path(&environment, format);
+        if let Ok(contents) = std::fs::read(&manifest_path) {
+            log::info!("reading site manifest from {}", manifest_path.display());
+            let mut manifest = ManifestFormat::of(&manifest_path)
+                .parse(&contents)
+                .context(ParseManifestSnafu {
+                    path: &manifest_path,
+                })?;
+            manifest.format = format.unwrap_or(manifest.format);
+            Ok(manifest)
         } else {
-            SiteManifest {
+            Ok(SiteManifest {
                 environment,
                 build_directory,
+                render_hash: None,
                 files: Default::default(),
+                format: format.unwrap_or_default(),
+            })
+        }
+    }
+
+    /// Read a manifest from the given path, optionally as it was at the given git ref.
+    pub fn read(path: impl AsRef<std::path::Path>, git_ref: Option<&str>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = if let Some(git_ref) = git_ref {
+            log::info!("reading site manifest {} at '{git_ref}'", path.display());
+            let context = GitManifestSnafu { path, git_ref };
+            let repo = git2::Repository::discover(".").context(context)?;
+            let spec = format!("{git_ref}:{}", path.display());
+            let object = repo.revparse_single(&spec).context(context)?;
+            let blob = object.peel_to_blob().context(context)?;
+            blob.content().to_vec()
+        } else {
+            log::info!("reading site manifest from {}", path.display());
+            std::fs::read(path).context(ReadManifestSnafu { path })?
+        };
+        ManifestFormat::of(path)
+            .parse(&contents)
+            .context(ParseManifestSnafu { path })
+    }
+
+    /// Save the manifest to `{environment}.yaml`, or the extension of its format,
+    /// removing any manifest of the environment in another format so it isn't
+    /// read instead.
+    pub fn save(&self) {
+        let manifest_string = self.format.serialize(self);
+        let manifest_path = format!("{}.{}", self.environment, self.format.extension());
+        std::fs::write(&manifest_path, manifest_string).unwrap();
+        log::info!("build manifest saved to '{manifest_path}'");
+        for format in ManifestFormat::ALL {
+            let path = format!("{}.{}", self.environment, format.extension());
+            if format == self.format || !std::path::Path::new(&path).exists() {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => log::info!("removed the {} manifest '{path}'", format.extension()),
+                Err(e) => log::warn!("could not remove the old manifest '{path}': {e}"),
             }
         }
     }
 
-    fn clean(&mut self) {
+    /// Remove and recreate the build directory, clearing the manifest's files.
+    pub fn clean(&mut self) {
         log::info!("cleaning '{}'", self.build_directory.display());
         if self.build_directory.is_dir() {
             log::debug!("removing build dir '{}'", self.build_directory.display());
@@ -209,109 +2211,526 @@ impl SiteManifest {
         self.files = Default::default();
     }
 
-    fn build_external<R: Renderer>(&mut self, cfg: &SiteConfig, external: ExternalPage) {
+    #[allow(clippy::too_many_arguments)]
+    fn build_external<R: Renderer>(
+        &mut self,
+        cfg: &SiteConfig,
+        options: &BuildOptions,
+        layout: Option<&Layout>,
+        variables: Option<&BTreeMap<&str, String>>,
+        external: ExternalPage,
+        content: String,
+        origin_modified: chrono::DateTime<chrono::FixedOffset>,
+    ) -> Result<(std::time::Duration, Option<search::SearchEntry>), Error> {
         let ExternalPage {
             source_url,
             local_path,
+            ..
         } = external;
+        let language = page_language(&options.languages, &local_path).map(|(language, _)| language);
+        let local_path = options.base_path().join(local_path);
         let built_filepath = self.build_directory.join(&local_path);
-        let (content, origin_modified) = match &source_url {
-            PageSource::Remote(url) => {
-                let content = String::from_utf8(
-                    std::process::Command::new("curl")
-                        .arg(url)
-                        .output()
-                        .expect("could not curl the devlog")
-                        .stdout,
-                )
-                .unwrap();
-                let head = String::from_utf8(
-                    std::process::Command::new("curl")
-                        .arg("--head")
-                        .arg(url)
-                        .output()
-                        .expect("could not curl the devlog")
-                        .stdout,
-                )
-                .unwrap();
-                log::info!("devlog: {head}");
-
-                let headers = head
-                    .lines()
-                    .filter_map(|line| line.split_once(':'))
-                    .collect::<HashMap<_, _>>();
-                let origin_modified = match headers.get("date") {
-                    None => {
-                        log::warn!("headers did not contain 'date'");
-                        chrono::Utc::now().fixed_offset()
-                    }
-                    Some(d) => {
-                        log::debug!("date: {d}");
-                        match chrono::DateTime::parse_from_rfc2822(d) {
-                            Err(e) => {
-                                log::error!("could not parse date: {e}");
-                                chrono::Utc::now().fixed_offset()
-                            }
-                            Ok(d) => d,
-                        }
-                    }
-                };
-                (content, origin_modified)
-            }
-            PageSource::Local(path) => {
-                let mut file = std::fs::File::open(path).unwrap();
-                let origin_modified = chrono::DateTime::<chrono::Utc>::from(
-                    file.metadata().unwrap().modified().unwrap(),
-                )
-                .fixed_offset();
-                let mut content = String::new();
-                file.read_to_string(&mut content).unwrap();
-                (content, origin_modified)
-            }
-        };
+        let source_hash = content_hash(content.as_bytes());
 
         log::trace!("rendering the devlog to {}", built_filepath.display());
-        let page_string = R::render_content(cfg, self.environment, content, "devlog").unwrap();
-        log::trace!("  writing");
-        if let Some(parent) = built_filepath.parent() {
-            std::fs::create_dir_all(parent).unwrap();
-        }
-        std::fs::write(&built_filepath, page_string).unwrap();
+        let render_options = options.render_options().with_front_matter(&content);
+        let mut page = PageContext::new(
+            cfg,
+            &self.environment,
+            &local_path,
+            "devlog",
+            render_options,
+        );
+        page.language = language;
+        page.base_path = options.base_url_path();
+        page.templates_dir = &options.templates_dir;
+        let render_started = std::time::Instant::now();
+        let origin = source_url.as_str();
+        let outputs =
+            R::render_outputs(cfg, &page, &content).map_err(|e| render_error(origin, e))?;
+        let search_content = options.search_index.then(|| content.clone());
+        let mut page_string =
+            R::render_page(cfg, &page, content).map_err(|e| render_error(origin, e))?;
+        let search_entry = search_content.and_then(|content| {
+            search::entry(
+                to_s3_key(&local_path),
+                page.url.clone(),
+                &content,
+                &page_string,
+            )
+        });
+        if let Some(layout) = layout {
+            page_string = layout.wrap(cfg, &page, &page_string);
+        }
+        if let Some(variables) = variables {
+            page_string = interpolate(&page_string, variables);
+        }
+        if options.strip_extension {
+            page_string = strip_html_extensions(&page_string, &(cfg.root_url)(&self.environment));
+        }
+        let render_duration = render_started.elapsed();
+        log::trace!("  writing");
+        if let Some(parent) = built_filepath.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        let bytes = options.process(&built_filepath, page_string.into_bytes());
+        let content_hash = content_hash(&bytes);
+        std::fs::write(&built_filepath, bytes).unwrap();
         log::trace!("  done!");
 
+        self.write_outputs(
+            options,
+            source_url.as_str(),
+            origin_modified,
+            FileKind::External,
+            outputs,
+        );
         self.files.insert(
             source_url.as_str().to_owned(),
             ManifestFile {
                 origin: source_url.as_str().to_owned(),
                 origin_modified,
+                content_type: Some(rendered_content_type(&local_path)),
+                kind: Some(FileKind::External),
+                content_hash,
+                source_hash: Some(source_hash),
                 destination: local_path,
                 built_filepath,
             },
         );
+        Ok((render_duration, search_entry))
+    }
+
+    /// Run a build hook's shell `command`, failing if it exits unsuccessfully.
+    async fn run_hook(&self, stage: &'static str, command: &str) -> Result<(), Error> {
+        log::info!("running the {stage} hook `{command}`");
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let status = tokio::process::Command::new(shell)
+            .arg(flag)
+            .arg(command)
+            .env("PUSHA_ENVIRONMENT", self.environment.to_string())
+            .env("PUSHA_BUILD_DIRECTORY", &self.build_directory)
+            .status()
+            .await
+            .context(RunHookSnafu { stage, command })?;
+        snafu::ensure!(
+            status.success(),
+            HookSnafu {
+                stage,
+                command,
+                status
+            }
+        );
+        Ok(())
     }
 
-    fn build<R: Renderer>(
+    /// Write the extra outputs rendered from the page at `origin`, recording each
+    /// in the manifest under "{origin}#{destination}".
+    fn write_outputs(
         &mut self,
-        cfg: &SiteConfig,
-        external_pages: impl IntoIterator<Item = ExternalPage>,
+        options: &BuildOptions,
+        origin: &str,
+        origin_modified: chrono::DateTime<chrono::FixedOffset>,
+        kind: FileKind,
+        outputs: Vec<(std::path::PathBuf, Vec<u8>)>,
     ) {
-        self.clean();
+        for (destination, bytes) in outputs {
+            let built_filepath = self.build_directory.join(&destination);
+            log::trace!("  writing output {}", built_filepath.display());
+            if let Some(parent) = built_filepath.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            let bytes = options.process(&built_filepath, bytes);
+            let content_hash = content_hash(&bytes);
+            std::fs::write(&built_filepath, bytes).unwrap();
+            self.files.insert(
+                format!("{origin}#{}", to_s3_key(&destination)),
+                ManifestFile {
+                    origin: origin.to_owned(),
+                    origin_modified,
+                    content_type: Some(rendered_content_type(&destination)),
+                    kind: Some(kind),
+                    content_hash,
+                    source_hash: None,
+                    destination,
+                    built_filepath,
+                },
+            );
+        }
+    }
+
+    /// Copy the asset `file` to `destination` in the build directory, processed
+    /// by `options`, along with its WebP variant if `options.optimize_images`.
+    fn build_asset(
+        &mut self,
+        options: &BuildOptions,
+        file: &std::path::Path,
+        destination: std::path::PathBuf,
+    ) -> Result<(), Error> {
+        let built_filepath = self.build_directory.join(&destination);
+        if let Some(parent) = built_filepath.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        log::trace!("copying {} to {}", file.display(), built_filepath.display());
+        if !file.exists() {
+            log::error!("file {} does not exist", file.display());
+        }
+
+        let origin = format!("{}", file.display());
+        let mut file = std::fs::File::open(file).unwrap();
+        let meta = file.metadata().unwrap();
+        let origin_modified =
+            chrono::DateTime::<chrono::Utc>::from(meta.modified().unwrap()).fixed_offset();
+        let mut bytes = vec![];
+        let _ = file.read_to_end(&mut bytes).unwrap();
+
+        let variant = if options.optimize_images
+            && images::is_optimizable(&built_filepath)
+            && bytes.len() as u64 <= options.image_max_size
+        {
+            log::trace!("generating webp variant of {}", built_filepath.display());
+            images::webp(&built_filepath, &bytes)
+        } else {
+            None
+        };
+
+        let bytes = options.process(&built_filepath, bytes);
+        let hash = content_hash(&bytes);
+        std::fs::write(&built_filepath, bytes).context(WriteFileSnafu {
+            path: &built_filepath,
+        })?;
+
+        if let Some(webp) = variant {
+            // Appending the suffix keeps "photo.png" and "photo.jpg" from sharing a variant.
+            let mut destination = destination.clone().into_os_string();
+            destination.push(".webp");
+            let destination = std::path::PathBuf::from(destination);
+            let built_filepath = self.build_directory.join(&destination);
+            let content_hash = content_hash(&webp);
+            std::fs::write(&built_filepath, webp).context(WriteFileSnafu {
+                path: &built_filepath,
+            })?;
+            self.files.insert(
+                format!("{origin}#webp"),
+                ManifestFile {
+                    origin: origin.clone(),
+                    origin_modified,
+                    built_filepath,
+                    content_type: Some(content_type(&destination)),
+                    kind: Some(FileKind::Assets),
+                    content_hash,
+                    source_hash: None,
+                    destination,
+                },
+            );
+        }
+
+        self.files.insert(
+            origin.clone(),
+            ManifestFile {
+                origin,
+                origin_modified,
+                built_filepath,
+                content_type: Some(content_type(&destination)),
+                kind: Some(FileKind::Assets),
+                content_hash: hash,
+                source_hash: None,
+                destination,
+            },
+        );
+        Ok(())
+    }
 
+    /// Build the site into the build directory, recording each built file.
+    pub async fn build<R: Renderer>(
+        &mut self,
+        cfg: &SiteConfig,
+        options: &BuildOptions,
+        external_pages: impl IntoIterator<Item = ExternalPage>,
+    ) -> Result<(), Error> {
+        let started = std::time::Instant::now();
+        if let Some(command) = options.pre_build_cmd.as_deref() {
+            self.run_hook("pre-build", command).await?;
+        }
+        let mut page_timings = vec![];
+        let mut search_entries = vec![];
+        let mut failures = BuildFailures::default();
         let content_dir = std::path::PathBuf::from("content");
+        let include = options.include_matcher()?;
+        let base_path = options.base_path();
+        let content_path = options.content_path();
+        let layout = (cfg.layout)(&self.environment)
+            .map(Layout::read)
+            .transpose()?;
+        let variables = options
+            .interpolate
+            .then(|| builtin_variables(&self.environment));
+
+        // External pages are read before cleaning, so pages that haven't changed
+        // since the last build can reuse their built files.
+        let fetcher = fetch::Fetcher::new(options.fetch_concurrency, options.fetch_rate)
+            .with_per_host(options.concurrency_per_host)
+            .with_timeout(options.http_timeout.map(std::time::Duration::from_secs));
+        let mut external_pages = external_pages.into_iter().collect::<Vec<_>>();
+        let has_external_pages = !external_pages.is_empty();
+        if include.is_some() {
+            log::info!("partial build, skipping external pages");
+            external_pages.clear();
+        }
+        // Previously built pages are only reused if they were rendered the same way.
+        let render_hash = render_hash::<R>(
+            cfg,
+            &self.environment,
+            options,
+            layout.as_ref(),
+            variables.as_ref(),
+        );
+        let reusable = self.render_hash.as_ref() == Some(&render_hash);
+        if has_external_pages && !reusable {
+            log::debug!("the build has changed, rendering every external page again");
+        }
+        let previous_files = &self.files;
+        let sources =
+            futures_util::future::join_all(external_pages.iter().map(|external_page| async {
+                let source = &external_page.source_url;
+                let cached = previous_files.get(source.as_str());
+                let previous = cached.filter(|_| reusable);
+                let modified_since = previous.map(|mf| mf.origin_modified);
+                let read = match source
+                    .read(&fetcher, &external_page.headers, modified_since)
+                    .await
+                {
+                    Err(e @ (Error::Fetch { .. } | Error::RemoteStatus { .. })) => {
+                        match options.on_remote_error {
+                            OnRemoteError::Fail => return Err(e),
+                            OnRemoteError::Skip => {
+                                log::warn!("skipping '{}': {e}", source.as_str());
+                                return Ok(ExternalSource::Skipped);
+                            }
+                            OnRemoteError::UseCached => {
+                                let Some((built, modified)) = cached.and_then(|mf| {
+                                    let built =
+                                        PreviousBuild::read(previous_files, source.as_str())
+                                            .ok()?;
+                                    Some((built, mf.origin_modified))
+                                }) else {
+                                    return Err(e);
+                                };
+                                log::warn!(
+                                    "using the previous build of '{}': {e}",
+                                    source.as_str()
+                                );
+                                return Ok(ExternalSource::Unchanged(
+                                    built,
+                                    modified,
+                                    cached.and_then(|mf| mf.source_hash.clone()),
+                                ));
+                            }
+                        }
+                    }
+                    read => read?,
+                };
+                if let Some((content, modified)) = read {
+                    // Upstreams without reliable modification headers are fetched
+                    // every time, so their content is compared too.
+                    let source_hash = content_hash(content.as_bytes());
+                    let unchanged = previous
+                        .filter(|mf| mf.source_hash.as_ref() == Some(&source_hash))
+                        .and_then(|_| PreviousBuild::read(previous_files, source.as_str()).ok());
+                    if let Some(built) = unchanged {
+                        log::debug!("'{}' has the same content as before", source.as_str());
+                        return Ok(ExternalSource::Unchanged(
+                            built,
+                            modified,
+                            Some(source_hash),
+                        ));
+                    }
+                    return Ok(ExternalSource::Read(content, modified));
+                }
+                // Only a conditional request is answered as not modified, which is
+                // only sent for a page that was built before.
+                let not_modified = RemoteStatusSnafu {
+                    page: source.as_str(),
+                    status: reqwest::StatusCode::NOT_MODIFIED,
+                };
+                let Some(previous) = previous else {
+                    return not_modified.fail();
+                };
+                match PreviousBuild::read(previous_files, source.as_str()) {
+                    Ok(built) => Ok(ExternalSource::Unchanged(
+                        built,
+                        previous.origin_modified,
+                        previous.source_hash.clone(),
+                    )),
+                    Err(e) => {
+                        log::warn!(
+                            "'{}' is not modified, but its built file could not be read: {e}",
+                            source.as_str()
+                        );
+                        let Some((content, modified)) =
+                            source.read(&fetcher, &external_page.headers, None).await?
+                        else {
+                            return not_modified.fail();
+                        };
+                        Ok(ExternalSource::Read(content, modified))
+                    }
+                }
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if include.is_some() {
+            std::fs::create_dir_all(&self.build_directory).unwrap();
+        } else if options.no_clean {
+            log::info!("not cleaning '{}'", self.build_directory.display());
+            std::fs::create_dir_all(&self.build_directory).unwrap();
+            self.files = Default::default();
+        } else {
+            self.clean();
+        }
 
-        for external_page in external_pages.into_iter() {
+        if include.is_none() {
+            self.render_hash = Some(render_hash);
+        }
+        for (external_page, source) in external_pages.into_iter().zip(sources) {
             log::trace!("Processing external page: {external_page:#?}");
 
-            self.build_external::<R>(cfg, external_page);
+            match source {
+                ExternalSource::Read(content, origin_modified) => {
+                    let origin = external_page.source_url.as_str().to_owned();
+                    let built = self.build_external::<R>(
+                        cfg,
+                        options,
+                        layout.as_ref(),
+                        variables.as_ref(),
+                        external_page,
+                        content,
+                        origin_modified,
+                    );
+                    let (duration, search_entry) = match built {
+                        Ok(built) => built,
+                        Err(e) => {
+                            failures.fail(options, e)?;
+                            continue;
+                        }
+                    };
+                    page_timings.push(PageTiming {
+                        origin,
+                        milliseconds: duration.as_secs_f64() * 1000.0,
+                        assets: vec![],
+                    });
+                    search_entries.extend(search_entry);
+                }
+                ExternalSource::Skipped => {}
+                ExternalSource::Unchanged(built, origin_modified, source_hash) => {
+                    let PreviousBuild { bytes, outputs } = built;
+                    log::info!(
+                        "'{}' is not modified, reusing the previous build",
+                        external_page.source_url.as_str()
+                    );
+                    let destination = base_path.join(&external_page.local_path);
+                    let built_filepath = self.build_directory.join(&destination);
+                    if let Some(parent) = built_filepath.parent() {
+                        std::fs::create_dir_all(parent).unwrap();
+                    }
+                    let content_hash = content_hash(&bytes);
+                    if options.search_index {
+                        search_entries.push(search::html_entry(
+                            to_s3_key(&destination),
+                            page_url(&(cfg.root_url)(&self.environment), &destination),
+                            &String::from_utf8_lossy(&bytes),
+                        ));
+                    }
+                    std::fs::write(&built_filepath, bytes).unwrap();
+                    self.files.insert(
+                        external_page.source_url.as_str().to_owned(),
+                        ManifestFile {
+                            origin: external_page.source_url.as_str().to_owned(),
+                            origin_modified,
+                            built_filepath,
+                            content_type: Some(rendered_content_type(&destination)),
+                            kind: Some(FileKind::External),
+                            content_hash,
+                            source_hash,
+                            destination,
+                        },
+                    );
+                    // The extra outputs rendered with the page are reused with it.
+                    for (key, mut mf, bytes) in outputs {
+                        mf.built_filepath = self.build_directory.join(&mf.destination);
+                        if let Some(parent) = mf.built_filepath.parent() {
+                            std::fs::create_dir_all(parent).unwrap();
+                        }
+                        std::fs::write(&mf.built_filepath, bytes).unwrap();
+                        self.files.insert(key, mf);
+                    }
+                }
+            }
+        }
+
+        // Sites made only of external pages don't need a content directory.
+        let mut files = if content_dir.exists() || !has_external_pages {
+            get_content_files(&content_dir, &self.environment)?
+        } else {
+            log::warn!(
+                "there is no '{}' directory, only external pages will be built",
+                content_dir.display()
+            );
+            vec![]
+        };
+        if let Some(ignore) = ignore_matcher()? {
+            files.retain(|path| {
+                let is_ignored = ignore.is_match(path);
+                if is_ignored {
+                    log::debug!("ignoring '{}'", path.display());
+                }
+                !is_ignored
+            });
+        }
+
+        // Variants are found among all pages, so partial builds still link them.
+        let root_url = (cfg.root_url)(&self.environment);
+        let mut variants = BTreeMap::<std::path::PathBuf, Vec<Alternate>>::new();
+        for file in files
+            .iter()
+            .filter(|path| path.extension() == Some("md".as_ref()))
+        {
+            let destination = markdown_destination(file, options.pretty_urls);
+            if let Some((language, path)) = page_language(&options.languages, &destination) {
+                variants.entry(path).or_default().push(Alternate {
+                    language: language.to_owned(),
+                    url: page_url(&root_url, &content_path.join(&destination)),
+                });
+            }
         }
 
-        let files = get_files(content_dir);
+        let files = match &include {
+            Some(include) => {
+                let files = files
+                    .into_iter()
+                    .filter(|path| include.is_match(path))
+                    .collect::<Vec<_>>();
+                log::info!(
+                    "building {} files matching {:?}",
+                    files.len(),
+                    options.include
+                );
+                files
+            }
+            None => files,
+        };
         let (markdown_files, other_files): (Vec<_>, Vec<_>) = files
             .into_iter()
             .partition(|path| path.extension().map(|ext| ext == "md").unwrap_or_default());
 
         for file in markdown_files {
-            let destination = pop_parent_replace_ext(&file, Some("html"));
+            let relative = markdown_destination(&file, options.pretty_urls);
+            let destination = content_path.join(&relative);
             let built_filepath = self.build_directory.join(&destination);
             log::trace!(
                 "rendering {} to {}",
@@ -327,19 +2746,76 @@ impl SiteManifest {
 
             let mut content = String::new();
             let _ = file.read_to_string(&mut content).unwrap();
-            let page_string = R::render_content(cfg, self.environment, content, "").unwrap();
+            let render_options = options.render_options().with_front_matter(&content);
+            let mut page =
+                PageContext::new(cfg, &self.environment, &destination, "", render_options);
+            page.base_path = options.base_url_path();
+            page.templates_dir = &options.templates_dir;
+            if let Some((language, path)) = page_language(&options.languages, &relative) {
+                page.language = Some(language);
+                page.alternates = variants[&path]
+                    .iter()
+                    .filter(|alternate| alternate.language != language)
+                    .cloned()
+                    .collect();
+            }
+            let render_started = std::time::Instant::now();
+            let rendered = R::render_outputs(cfg, &page, &content).and_then(|outputs| {
+                let search_content = options.search_index.then(|| content.clone());
+                Ok((
+                    outputs,
+                    search_content,
+                    R::render_page(cfg, &page, content)?,
+                ))
+            });
+            let (outputs, search_content, mut page_string) = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    failures.fail(options, render_error(&origin, e))?;
+                    continue;
+                }
+            };
+            if let Some(content) = search_content {
+                search_entries.extend(search::entry(
+                    to_s3_key(&destination),
+                    page.url.clone(),
+                    &content,
+                    &page_string,
+                ));
+            }
+            if let Some(layout) = layout.as_ref() {
+                page_string = layout.wrap(cfg, &page, &page_string);
+            }
+            if let Some(variables) = variables.as_ref() {
+                page_string = interpolate(&page_string, variables);
+            }
+            if options.strip_extension {
+                page_string = strip_html_extensions(&page_string, &root_url);
+            }
+            page_timings.push(PageTiming {
+                origin: origin.clone(),
+                milliseconds: render_started.elapsed().as_secs_f64() * 1000.0,
+                assets: vec![],
+            });
             log::trace!("  writing");
             if let Some(parent) = built_filepath.parent() {
                 std::fs::create_dir_all(parent).unwrap();
             }
-            std::fs::write(&built_filepath, page_string).unwrap();
+            let bytes = options.process(&built_filepath, page_string.into_bytes());
+            let content_hash = content_hash(&bytes);
+            std::fs::write(&built_filepath, bytes).unwrap();
             log::trace!("  done!");
 
+            self.write_outputs(options, &origin, origin_modified, FileKind::Pages, outputs);
             self.files.insert(
                 origin.clone(),
                 ManifestFile {
                     origin,
                     origin_modified,
+                    content_type: Some(rendered_content_type(&destination)),
+                    kind: Some(FileKind::Pages),
+                    content_hash,
+                    source_hash: None,
                     destination,
                     built_filepath,
                 },
@@ -347,200 +2823,3273 @@ impl SiteManifest {
         }
 
         for file in other_files {
-            let destination = pop_parent_replace_ext(&file, None);
-            let built_filepath = self.build_directory.join(&destination);
-            if let Some(parent) = built_filepath.parent() {
-                std::fs::create_dir_all(parent).unwrap();
+            let destination = content_path.join(pop_parent_replace_ext(&file, None));
+            self.build_asset(options, &file, destination)?;
+        }
+
+        failures.finish()?;
+
+        if options.search_index {
+            if include.is_some() {
+                log::info!("partial build, keeping the previous search index");
+            } else {
+                self.write_search_index(&base_path, search_entries);
+            }
+        }
+        if base_path.as_os_str().is_empty() {
+            self.write_robots_txt(cfg);
+        } else {
+            log::debug!("not generating robots.txt, crawlers only read it from the root");
+        }
+        let collisions = self.collisions();
+        for collision in collisions.iter() {
+            if options.allow_collisions {
+                log::warn!("{collision}, the last one built wins");
+            } else {
+                log::error!("{collision}");
             }
-            log::trace!("copying {} to {}", file.display(), built_filepath.display());
-            if !file.exists() {
-                log::error!("file {} does not exist", file.display());
+        }
+        snafu::ensure!(
+            options.allow_collisions || collisions.is_empty(),
+            CollisionsSnafu {
+                count: collisions.len()
             }
+        );
+        if !options.no_clean {
+            self.remove_orphans()?;
+        }
+        if let Some(command) = options.post_build_cmd.as_deref() {
+            self.run_hook("post-build", command).await?;
+        }
+        if options.timings.is_some() || options.preload_map.is_some() {
+            let preloads = self.page_preloads(&root_url);
+            for timing in page_timings.iter_mut() {
+                if let Some((_, preloads)) = preloads.get(&timing.origin) {
+                    timing.assets = preloads.assets.iter().map(|a| a.href.clone()).collect();
+                }
+            }
+            if let Some(path) = options.preload_map.as_deref() {
+                log::info!("writing the preload map to '{}'", path.display());
+                let map = preloads.into_values().collect::<BTreeMap<_, _>>();
+                std::fs::write(path, serde_json::to_string_pretty(&map).unwrap())
+                    .context(WriteFileSnafu { path })?;
+            }
+        }
+        BuildTimings {
+            milliseconds: started.elapsed().as_secs_f64() * 1000.0,
+            pages: page_timings,
+        }
+        .report(options.slowest, options.timings.as_deref())
+    }
 
-            let origin = format!("{}", file.display());
-            let mut file = std::fs::File::open(file).unwrap();
-            let meta = file.metadata().unwrap();
-            let origin_modified =
-                chrono::DateTime::<chrono::Utc>::from(meta.modified().unwrap()).fixed_offset();
-            let mut bytes = vec![];
-            let _ = file.read_to_end(&mut bytes).unwrap();
-
-            std::fs::write(&built_filepath, bytes).unwrap();
+    /// Remove any files in the build directory that aren't referenced by the manifest,
+    /// so stale outputs of renamed or deleted sources aren't deployed.
+    fn remove_orphans(&self) -> Result<(), Error> {
+        let referenced = self
+            .files
+            .values()
+            .map(|mf| mf.built_filepath.as_path())
+            .collect::<std::collections::BTreeSet<_>>();
+        for path in get_files(&self.build_directory)? {
+            if !referenced.contains(path.as_path()) {
+                log::info!("removing orphaned built file '{}'", path.display());
+                std::fs::remove_file(&path).unwrap();
+            }
+        }
+        Ok(())
+    }
 
-            self.files.insert(
-                origin.clone(),
-                ManifestFile {
-                    origin,
-                    origin_modified,
-                    built_filepath,
-                    destination,
-                },
-            );
+    /// Compare this manifest against `other`, keyed by origin.
+    ///
+    /// Entries are modified if their content hashes differ. When either manifest
+    /// predates content hashes, they are modified if their origin modification
+    /// times or built file contents differ.
+    pub fn diff(&self, other: &SiteManifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        for (key, mfile) in other.files.iter() {
+            match self.files.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(previous) => {
+                    let modified =
+                        if previous.content_hash.is_empty() || mfile.content_hash.is_empty() {
+                            previous.origin_modified != mfile.origin_modified
+                                || std::fs::read(&previous.built_filepath).ok()
+                                    != std::fs::read(&mfile.built_filepath).ok()
+                        } else {
+                            previous.content_hash != mfile.content_hash
+                        };
+                    if modified {
+                        diff.modified.push(key.clone());
+                    }
+                }
+            }
         }
+        diff.removed = self
+            .files
+            .keys()
+            .filter(|key| !other.files.contains_key(*key))
+            .cloned()
+            .collect();
+        diff
+    }
 
-        let manifest_string = serde_yaml::to_string(&self).unwrap();
-        let manifest_path = format!("{}.yaml", self.environment);
-        std::fs::write(&manifest_path, manifest_string).unwrap();
-        log::info!("build manifest saved to '{manifest_path}'");
+    /// Upload one asset. With `create_only`, an existing object with the same key
+    /// is not replaced and [`Error::AlreadyExists`] is returned instead. With
+    /// `verify`, the stored object is checked against the local file afterwards.
+    pub async fn upload<S: ObjectStore>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        path: std::path::PathBuf,
+        key: String,
+        create_only: bool,
+        verify: bool,
+    ) -> Result<(), Error> {
+        let content_type = content_type(&path);
+        let object = PutObject {
+            key,
+            path,
+            content_type,
+            cache_control: None,
+            content_encoding: None,
+            tags: BTreeMap::new(),
+            content_hash: None,
+            create_only,
+        };
+        self.put(cfg, store, object.clone()).await?;
+        if verify {
+            verify_upload(store, &object).await?;
+        }
+        Ok(())
     }
 
-    /// Upload one asset.
-    async fn upload(&self, cfg: &SiteConfig, path: std::path::PathBuf, key: String) {
-        let bucket = if let Some(b) = (cfg.s3_bucket)(self.environment) {
-            b
-        } else {
-            log::error!("asset cannot be uploaded to a local environment");
-            panic!("environment error");
+    /// Upload a built file, as the content type recorded in the manifest.
+    ///
+    /// With `compression`, text files are compressed first.
+    async fn upload_file<S: ObjectStore>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        mfile: &ManifestFile,
+        compression: Option<Compression>,
+    ) -> Result<(), Error> {
+        let content_type = mfile
+            .content_type
+            .clone()
+            .unwrap_or_else(|| content_type(&mfile.built_filepath));
+        let object = PutObject {
+            key: to_s3_key(&mfile.destination),
+            path: mfile.built_filepath.clone(),
+            content_type,
+            cache_control: None,
+            content_encoding: None,
+            tags: BTreeMap::new(),
+            content_hash: (!mfile.content_hash.is_empty()).then(|| mfile.content_hash.clone()),
+            create_only: false,
+        };
+        let Some(compression) = compression.filter(|_| is_text(&object.content_type)) else {
+            return self.put(cfg, store, object).await;
         };
 
-        let config = aws_config::load_from_env()
-            .await
-            .to_builder()
-            .region(aws_config::Region::new("us-west-1"))
-            .build();
-        let s3 = aws_sdk_s3::Client::new(&config);
-        let content_type = new_mime_guess::from_path(&path).first_or_octet_stream();
-        log::info!("uploading '{bucket}' '{key}' as {content_type}");
-        let result = s3
-            .put_object()
-            .bucket(bucket)
-            .key(&key)
-            .content_type(content_type.essence_str())
-            .body(
-                aws_sdk_s3::primitives::ByteStream::from_path(&path)
-                    .await
-                    .unwrap(),
+        let path = &mfile.built_filepath;
+        let bytes = std::fs::read(path).context(CompressSnafu { path })?;
+        let compressed_dir =
+            std::env::temp_dir().join(format!("pusha-compressed-{}", std::process::id()));
+        for (encoding, suffix) in compression.encodings() {
+            let content_encoding = encoding.content_encoding();
+            let compressed_path =
+                compressed_dir.join(format!("{}{suffix}.{content_encoding}", object.key));
+            if let Some(parent) = compressed_path.parent() {
+                std::fs::create_dir_all(parent).context(CompressSnafu { path })?;
+            }
+            std::fs::write(&compressed_path, encoding.compress(&bytes))
+                .context(CompressSnafu { path })?;
+            let compressed = PutObject {
+                key: format!("{}{suffix}", object.key),
+                path: compressed_path.clone(),
+                content_encoding: Some(content_encoding.to_owned()),
+                ..object.clone()
+            };
+            let result = self.put(cfg, store, compressed).await;
+            let _ = std::fs::remove_file(&compressed_path);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Upload an object, with the `Cache-Control` configured for its key.
+    async fn put<S: ObjectStore>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        mut object: PutObject,
+    ) -> Result<(), Error> {
+        object.key = to_s3_key(&object.key);
+        object.cache_control = (cfg.cache_control)(&self.environment, &object.key);
+        object.tags = (cfg.object_tags)(&self.environment);
+        if object.tags.values().any(|value| value.contains("${")) {
+            let variables = builtin_variables(&self.environment);
+            for value in object.tags.values_mut() {
+                *value = interpolate(value, &variables);
+            }
+        }
+        let key = object.key.clone();
+        log::info!(
+            "uploading '{}' '{key}' as {}",
+            store.name(),
+            object.content_type
+        );
+        if let Some(cache_control) = object.cache_control.as_deref() {
+            log::debug!("  with cache control '{cache_control}'");
+        }
+        store.put_object(object).await?;
+
+        log::info!("uploaded: {}/{key}", (cfg.root_url)(&self.environment));
+        Ok(())
+    }
+
+    /// Upload the given files and invalidate their paths.
+    pub async fn publish<'a, S: ObjectStore, C: CacheInvalidator>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        invalidator: &C,
+        files: impl IntoIterator<Item = &'a ManifestFile>,
+        commit: Option<&str>,
+    ) -> Result<(), Error> {
+        let files = files.into_iter().collect::<Vec<_>>();
+        check_built_files(files.iter().copied())?;
+        self.upload_files(cfg, store, &files, None).await?;
+
+        log::info!("done uploading to s3");
+        let paths = files
+            .iter()
+            .map(|mf| format!("/{}", to_s3_key(&mf.destination)))
+            .collect::<Vec<_>>();
+        self.invalidate(invalidator, paths, commit, false).await?;
+        Ok(())
+    }
+
+    /// Upload the given files, compressing text files with `compression`.
+    ///
+    /// Every file is attempted, and if any fail, the error lists each of them.
+    async fn upload_files<S: ObjectStore>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        files: &[&ManifestFile],
+        compression: Option<Compression>,
+    ) -> Result<(), Error> {
+        // The store bounds how many of these run at once.
+        let results = futures_util::future::join_all(
+            files
+                .iter()
+                .map(|mfile| self.upload_file(cfg, store, mfile, compression)),
+        )
+        .await;
+        let failures = files
+            .iter()
+            .zip(results)
+            .filter_map(|(mfile, result)| Some((to_s3_key(&mfile.destination), result.err()?)))
+            .collect::<Vec<_>>();
+        for (key, e) in failures.iter() {
+            log::error!("could not upload '{key}': {e}");
+        }
+        snafu::ensure!(failures.is_empty(), UploadsSnafu { failures });
+        Ok(())
+    }
+
+    /// Returns the files whose content hash differs from the one stored with their
+    /// object, or that have no object.
+    async fn changed_files<'a, S: ObjectStore>(
+        &self,
+        store: &S,
+        files: Vec<&'a ManifestFile>,
+    ) -> Result<Vec<&'a ManifestFile>, Error> {
+        let stored = futures_util::future::try_join_all(files.iter().map(|mf| async move {
+            let head = store.head_object(&to_s3_key(&mf.destination)).await?;
+            Ok::<_, Error>(head.and_then(|head| head.content_hash))
+        }))
+        .await?;
+        let total = files.len();
+        let changed = files
+            .into_iter()
+            .zip(stored)
+            .filter(|(mf, stored)| {
+                mf.content_hash.is_empty() || stored.as_ref() != Some(&mf.content_hash)
+            })
+            .map(|(mf, _)| mf)
+            .collect::<Vec<_>>();
+        log::info!(
+            "{} of {total} files changed, skipping the rest",
+            changed.len()
+        );
+        Ok(changed)
+    }
+
+    /// Delete the `stored` objects, as listed by [`stored_keys`], that aren't in
+    /// the manifest, returning their keys.
+    ///
+    /// The candidates are always logged first. Nothing is deleted on a dry run,
+    /// and production asks for confirmation unless `options.yes` is set. Objects
+    /// under "uploads/" are kept, since they aren't built, and so are the brotli
+    /// copies uploaded with `--compress both`.
+    async fn delete_orphans<S: ObjectStore>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        stored: std::collections::BTreeSet<String>,
+        options: &DeployOptions,
+    ) -> Result<Vec<String>, Error> {
+        let suffixes = std::iter::once("")
+            .chain(
+                options
+                    .compress
+                    .map_or(&[][..], Compression::encodings)
+                    .iter()
+                    .map(|(_, suffix)| *suffix),
             )
-            .send()
-            .await;
-        if let Err(e) = result {
-            log::error!("{e}");
-            panic!("s3 upload failed: {e:#?}");
+            .collect::<std::collections::BTreeSet<_>>();
+        let deployed = self
+            .files
+            .values()
+            .flat_map(|mf| {
+                let key = to_s3_key(&mf.destination);
+                suffixes.iter().map(move |suffix| format!("{key}{suffix}"))
+            })
+            .collect::<std::collections::BTreeSet<_>>();
+        let orphans = stored
+            .into_iter()
+            .filter(|key| !deployed.contains(key) && !key.starts_with(UPLOADS_PREFIX))
+            .collect::<Vec<_>>();
+        if orphans.is_empty() {
+            log::info!("there are no orphaned objects in '{}'", store.name());
+            return Ok(vec![]);
         }
 
-        log::info!("uploaded: {}/{key}", (cfg.root_url)(self.environment));
+        log::warn!(
+            "{} orphaned objects in '{}' are not in the manifest:",
+            orphans.len(),
+            store.name()
+        );
+        for key in orphans.iter() {
+            log::warn!("  {key}");
+        }
+        if options.dry_run {
+            log::warn!("dry run, not deleting orphaned objects");
+            return Ok(vec![]);
+        }
+        if self.environment == Environment::Production && !options.yes {
+            confirm(
+                cfg,
+                &options.aws,
+                "delete orphaned objects from",
+                &self.environment,
+            )?;
+        }
+        store.delete_objects(orphans.clone()).await?;
+        Ok(orphans)
     }
 
-    async fn deploy<R: Renderer>(
+    /// Build the site, unless `options.no_build` is set, then upload it and
+    /// invalidate the cloudfront cache.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn deploy<R: Renderer, S: ObjectStore, C: CacheInvalidator>(
         &mut self,
         cfg: &SiteConfig,
+        store: &S,
+        invalidator: &C,
+        build_options: &BuildOptions,
+        options: &DeployOptions,
         external_pages: impl IntoIterator<Item = ExternalPage>,
-    ) {
+        commit: Option<String>,
+    ) -> Result<DeploySummary, Error> {
+        let exclude_from_invalidation = glob_set(&options.exclude_from_invalidation)?;
         log::info!(
             "deploying with configuration: {:#?}",
             [
-                ("root url", (cfg.root_url)(self.environment)),
+                ("root url", (cfg.root_url)(&self.environment)),
                 (
                     "s3 bucket",
-                    (cfg.s3_bucket)(self.environment).unwrap_or("(none)")
+                    options
+                        .aws
+                        .bucket(cfg, &self.environment)
+                        .unwrap_or_else(|| "(none)".to_owned())
                 ),
                 (
                     "cloudfront distribution",
-                    (cfg.cloudfront_distro)(self.environment).unwrap_or("(none)")
+                    distribution_names(&options.aws.distributions(cfg, &self.environment))
                 ),
             ]
         );
 
-        self.build::<R>(cfg, external_pages);
-
-        let config = aws_config::load_from_env()
-            .await
-            .to_builder()
-            .region(aws_config::Region::new("us-west-1"))
-            .build();
-        for mfile in self.files.values() {
-            let key = format!("{}", mfile.destination.display());
-            self.upload(cfg, mfile.built_filepath.clone(), key).await;
-        }
-
-        log::info!("done uploading to s3, invalidating the cloudfront cache");
-        let hash = String::from_utf8(
-            std::process::Command::new("git")
-                .args(["rev-parse", "HEAD"])
-                .output()
-                .expect("Could not get commit hash")
-                .stdout,
-        )
-        .expect("not utf8");
-        let cf = aws_sdk_cloudfront::Client::new(&config);
-        let paths = self
+        // Listing the bucket is slow, so unless orphans are needed new files are
+        // told apart from changed ones by the previous manifest.
+        let previous = self
             .files
             .values()
-            .map(|mf| format!("/{}", mf.destination.display()))
+            .map(|mf| to_s3_key(&mf.destination))
+            .collect::<std::collections::BTreeSet<_>>();
+        if options.no_build || options.manifest_only {
+            snafu::ensure!(
+                !self.files.is_empty(),
+                NoManifestSnafu {
+                    environment: self.environment.clone()
+                }
+            );
+            log::info!("deploying the existing build of {} files", self.files.len());
+        } else {
+            self.build::<R>(cfg, build_options, external_pages).await?;
+        }
+        // A reused build has the info of its previous deploy, which is replaced.
+        self.files.remove(DEPLOY_INFO);
+
+        let changed = options.since.as_deref().map(changed_since).transpose()?;
+        let content_dirs = [
+            std::path::PathBuf::from("content"),
+            std::path::PathBuf::from(format!("content.{}", self.environment)),
+        ];
+        let environment = self.environment.clone();
+        for mf in self.files.values() {
+            if is_manifest_file(&mf.destination, &environment) {
+                log::warn!(
+                    "not deploying '{}', it is named like a manifest",
+                    mf.destination.display()
+                );
+            }
+        }
+        // Files that don't come from the content directories aren't tracked by git,
+        // so they are always deployed.
+        let deploys = |mf: &&ManifestFile| {
+            let is_changed = match &changed {
+                None => true,
+                Some(changed) => {
+                    let origin = std::path::Path::new(&mf.origin);
+                    changed.contains(origin) || !content_dirs.iter().any(|d| origin.starts_with(d))
+                }
+            };
+            is_changed
+                && options.only.is_none_or(|kind| mf.kind() == kind)
+                && !is_manifest_file(&mf.destination, &environment)
+        };
+
+        // Nothing is uploaded unless everything can be, so a missing file can't
+        // leave a partial deploy behind.
+        check_built_files(self.files.values().filter(deploys))?;
+        let info = self.write_deploy_info(&build_options.base_path(), commit.clone())?;
+        let to_deploy = self.files.values().filter(deploys).collect::<Vec<_>>();
+        if let Some(kind) = options.only {
+            log::info!("deploying only {kind:?}");
+        }
+        if changed.is_some() {
+            log::info!(
+                "deploying {} of {} files changed since '{}'",
+                to_deploy.len(),
+                self.files.len(),
+                options.since.as_deref().unwrap_or_default()
+            );
+        }
+
+        let to_deploy = if options.manifest_only {
+            self.changed_files(store, to_deploy).await?
+        } else {
+            to_deploy
+        };
+
+        if options.configure_website {
+            self.configure_website(store).await?;
+        }
+
+        let stored = if options.delete_orphans || options.manifest_only {
+            Some(stored_keys(store, &build_options.base_path()).await?)
+        } else {
+            None
+        };
+        let new = to_deploy
+            .iter()
+            .filter(|mf| {
+                !stored
+                    .as_ref()
+                    .unwrap_or(&previous)
+                    .contains(&to_s3_key(&mf.destination))
+            })
+            .count();
+        self.upload_files(cfg, store, &to_deploy, options.compress)
+            .await?;
+        let deleted = match stored {
+            Some(stored) => self.delete_orphans(cfg, store, stored, options).await?,
+            None => vec![],
+        };
+        let mut summary = DeploySummary {
+            new,
+            changed: to_deploy.len() - new,
+            skipped: self.files.len() - to_deploy.len(),
+            deleted: deleted.len(),
+            invalidated: 0,
+        };
+
+        log::info!("done uploading to s3");
+        let paths = to_deploy
+            .iter()
+            .map(|mf| to_s3_key(&mf.destination))
+            .chain(deleted)
+            .filter(|key| {
+                let is_excluded = exclude_from_invalidation
+                    .as_ref()
+                    .is_some_and(|exclude| exclude.is_match(key));
+                if is_excluded {
+                    log::debug!("not invalidating '{key}'");
+                }
+                !is_excluded
+            })
+            .map(|key| format!("/{key}"))
             .collect::<Vec<_>>();
-        log::debug!("paths: {paths:#?}");
-        let result = cf
-            .create_invalidation()
-            .distribution_id((cfg.cloudfront_distro)(self.environment).unwrap())
-            .invalidation_batch(
-                aws_sdk_cloudfront::types::InvalidationBatch::builder()
-                    .paths(
-                        aws_sdk_cloudfront::types::Paths::builder()
-                            .quantity(paths.len() as i32)
-                            .set_items(Some(paths))
-                            .build()
-                            .unwrap(),
-                    )
-                    .caller_reference(format!("xtask-{hash}"))
-                    .build()
-                    .unwrap(),
+        summary.invalidated = self
+            .invalidate(
+                invalidator,
+                paths,
+                commit.as_deref(),
+                options.idempotent_invalidation,
             )
-            .send()
-            .await;
-        match result {
-            Ok(invalidation) => {
-                log::info!("created invalidation: {invalidation:#?}");
+            .await?;
+
+        let mut line = serde_json::to_string(&info).unwrap();
+        line.push('\n');
+        let log_path = std::path::Path::new("deploys.log");
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .and_then(|mut log_file| log_file.write_all(line.as_bytes()))
+            .context(WriteFileSnafu { path: log_path })?;
+        log::info!("deploy recorded in 'deploys.log'");
+        log::info!("{summary}");
+        Ok(summary)
+    }
+
+    /// Returns a description of every problem with the manifest that would break a
+    /// deploy: built files that don't exist, destinations shared by more than one
+    /// file, and destinations that aren't well-formed s3 keys.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.collisions();
+        for (origin, mf) in self.files.iter() {
+            if !mf.built_filepath.is_file() {
+                problems.push(format!(
+                    "'{origin}' was built to '{}', which does not exist",
+                    mf.built_filepath.display()
+                ));
             }
-            Err(e) => {
-                log::error!("{e}");
-                panic!("cloudfront error: {e:#?}");
+            let destination = mf.destination.to_string_lossy();
+            let key = to_s3_key(&mf.destination);
+            if key.is_empty()
+                || key != destination
+                || key.len() > MAX_KEY_LENGTH
+                || key.chars().any(char::is_control)
+            {
+                problems.push(format!(
+                    "'{origin}' has the destination '{destination}', which is not a well-formed s3 key"
+                ));
             }
         }
+        problems
     }
-}
-
-pub async fn run<R: Renderer>(
-    cfg: &SiteConfig,
-    external_pages: impl IntoIterator<Item = ExternalPage>,
-) {
-    env_logger::builder().init();
-
-    let cli = Cli::parse();
 
-    let mut manifest = SiteManifest::new(cli.environment, cli.build_directory.into());
-
-    match cli.cmd {
-        Command::Deploy => {
-            manifest.deploy::<R>(cfg, external_pages).await;
-            log::info!("manifest: {manifest:#?}");
+    /// Returns a description of every destination shared by more than one file.
+    fn collisions(&self) -> Vec<String> {
+        let mut origins = BTreeMap::<&std::path::Path, Vec<&str>>::new();
+        for (origin, mf) in self.files.iter() {
+            origins.entry(&mf.destination).or_default().push(origin);
         }
-        Command::Build => manifest.build::<R>(cfg, external_pages),
-        Command::Clean => manifest.clean(),
-        Command::Upload { path, key } => {
-            let key = key.unwrap_or_else(|| {
-                let filename = path.file_name().unwrap().to_string_lossy().to_string();
+        origins
+            .into_iter()
+            .filter(|(_, origins)| origins.len() > 1)
+            .map(|(destination, origins)| {
                 format!(
-                    "uploads/{}",
-                    filename
-                        .replace(" ", "_")
-                        .split_whitespace()
-                        .collect::<Vec<_>>()
-                        .concat()
+                    "'{}' all have the destination '{}'",
+                    origins.join("', '"),
+                    destination.display()
                 )
-            });
-            manifest.upload(cfg, path, key).await
+            })
+            .collect()
+    }
+
+    /// Returns the path each built page is served at and its critical assets,
+    /// keyed by origin.
+    fn page_preloads(&self, root_url: &str) -> BTreeMap<String, (String, preload::PagePreloads)> {
+        self.files
+            .iter()
+            .filter(|(_, file)| matches!(file.kind(), FileKind::Pages | FileKind::External))
+            .filter(|(_, file)| file.destination.extension() == Some("html".as_ref()))
+            .filter_map(|(origin, file)| {
+                let html = std::fs::read_to_string(&file.built_filepath).ok()?;
+                let path = format!("/{}", to_s3_key(&file.destination));
+                Some((
+                    origin.clone(),
+                    (path, preload::PagePreloads::new(&html, root_url)),
+                ))
+            })
+            .collect()
+    }
+
+    /// Write the search index of the rendered pages into the build directory under
+    /// `base_path`, and add it to the manifest.
+    fn write_search_index(
+        &mut self,
+        base_path: &std::path::Path,
+        entries: Vec<search::SearchEntry>,
+    ) {
+        let destination = base_path.join(search::SEARCH_INDEX);
+        log::info!("indexing {} pages for search", entries.len());
+        let built_filepath = self.build_directory.join(&destination);
+        let contents = serde_json::to_string(&entries).unwrap();
+        let content_hash = content_hash(contents.as_bytes());
+        std::fs::write(&built_filepath, contents).unwrap();
+        self.files.insert(
+            search::SEARCH_INDEX.to_owned(),
+            ManifestFile {
+                origin: search::SEARCH_INDEX.to_owned(),
+                origin_modified: chrono::Utc::now().fixed_offset(),
+                built_filepath,
+                content_type: Some(content_type(&destination)),
+                kind: Some(FileKind::Assets),
+                content_hash,
+                source_hash: None,
+                destination,
+            },
+        );
+    }
+
+    /// Write a `robots.txt` into the build directory and add it to the manifest,
+    /// unless the content already has one.
+    ///
+    /// Its contents come from [`SiteConfig::robots_txt`], or by default allow
+    /// everything in production and disallow everything elsewhere.
+    fn write_robots_txt(&mut self, cfg: &SiteConfig) {
+        let destination = std::path::PathBuf::from("robots.txt");
+        if self
+            .files
+            .values()
+            .any(|mf| mf.destination == destination && mf.origin != "robots.txt")
+        {
+            log::debug!("using the robots.txt from the content");
+            return;
+        }
+
+        let contents = (cfg.robots_txt)(&self.environment).unwrap_or_else(|| {
+            if self.environment == Environment::Production {
+                let mut contents = "User-agent: *\nAllow: /\n".to_owned();
+                if self
+                    .files
+                    .values()
+                    .any(|mf| mf.destination == std::path::Path::new("sitemap.xml"))
+                {
+                    let root_url = (cfg.root_url)(&self.environment);
+                    contents.push_str(&format!(
+                        "Sitemap: {}/sitemap.xml\n",
+                        root_url.trim_end_matches('/')
+                    ));
+                }
+                contents
+            } else {
+                "User-agent: *\nDisallow: /\n".to_owned()
+            }
+        });
+        log::info!("generating robots.txt for '{}'", self.environment);
+        let built_filepath = self.build_directory.join(&destination);
+        let content_hash = content_hash(contents.as_bytes());
+        std::fs::write(&built_filepath, contents).unwrap();
+        self.files.insert(
+            "robots.txt".to_owned(),
+            ManifestFile {
+                origin: "robots.txt".to_owned(),
+                origin_modified: chrono::Utc::now().fixed_offset(),
+                built_filepath,
+                content_type: Some(content_type(&destination)),
+                kind: Some(FileKind::Assets),
+                content_hash,
+                source_hash: None,
+                destination,
+            },
+        );
+    }
+
+    /// Write `deploy-info.json` under `base_path` in the build directory and add it
+    /// to the manifest.
+    fn write_deploy_info(
+        &mut self,
+        base_path: &std::path::Path,
+        commit: Option<String>,
+    ) -> Result<DeployInfo, Error> {
+        let info = DeployInfo {
+            commit,
+            timestamp: chrono::Utc::now().fixed_offset(),
+            environment: self.environment.clone(),
+            deployed_by: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .ok(),
+            file_count: self.files.len(),
+        };
+        let destination = base_path.join(DEPLOY_INFO);
+        let built_filepath = self.build_directory.join(&destination);
+        let contents = serde_json::to_string_pretty(&info).unwrap();
+        let content_hash = content_hash(contents.as_bytes());
+        std::fs::write(&built_filepath, contents).context(WriteFileSnafu {
+            path: &built_filepath,
+        })?;
+        self.files.insert(
+            DEPLOY_INFO.to_owned(),
+            ManifestFile {
+                origin: DEPLOY_INFO.to_owned(),
+                origin_modified: info.timestamp,
+                built_filepath,
+                content_type: Some(content_type(&destination)),
+                kind: Some(FileKind::Assets),
+                content_hash,
+                source_hash: None,
+                destination,
+            },
+        );
+        self.save();
+        Ok(info)
+    }
+
+    /// Configure the bucket for static website hosting, serving "index.html" for
+    /// directories and "404.html" for missing pages.
+    async fn configure_website<S: ObjectStore>(&self, store: &S) -> Result<(), Error> {
+        if !self
+            .files
+            .values()
+            .any(|mf| mf.destination == std::path::Path::new("404.html"))
+        {
+            log::warn!("there is no '404.html' page, add one at 'content/404.md'");
+        }
+
+        log::info!("configuring '{}' for website hosting", store.name());
+        store
+            .configure_website(Some(&Website {
+                index_document: Some("index.html".to_owned()),
+                error_document: Some("404.html".to_owned()),
+                ..Website::default()
+            }))
+            .await
+    }
+
+    /// Delete one object.
+    async fn delete<S: ObjectStore>(&self, store: &S, key: String) -> Result<(), Error> {
+        log::info!("deleting '{}' '{key}'", store.name());
+        store.delete_object(&key).await
+    }
+
+    /// Invalidate the given paths in the cloudfront cache, along with the directory
+    /// of each `index.html`, returning the number of paths invalidated.
+    async fn invalidate<C: CacheInvalidator>(
+        &self,
+        invalidator: &C,
+        paths: Vec<String>,
+        commit: Option<&str>,
+        idempotent: bool,
+    ) -> Result<usize, Error> {
+        if paths.is_empty() {
+            log::info!("nothing was uploaded or deleted, skipping invalidation");
+            return Ok(0);
+        }
+        let paths = with_directory_paths(paths);
+        let caller_reference =
+            caller_reference(commit, idempotent, chrono::Utc::now().timestamp_millis());
+        log::info!("invalidating {} paths in the cloudfront cache", paths.len());
+        log::debug!("paths: {paths:#?}");
+        let count = paths.len();
+        invalidator.invalidate(paths, &caller_reference).await?;
+        Ok(count)
+    }
+
+    /// Delete every object whose key starts with `prefix` and remove the files
+    /// deployed under it from the manifest, returning the number of objects deleted.
+    pub async fn purge<S: ObjectStore>(&mut self, store: &S, prefix: &str) -> Result<usize, Error> {
+        let keys = store.list_objects(prefix).await?;
+        log::info!(
+            "purging {} objects under '{prefix}' from '{}'",
+            keys.len(),
+            store.name()
+        );
+        let count = keys.len();
+        store.delete_objects(keys).await?;
+
+        self.files
+            .retain(|_, mf| !to_s3_key(&mf.destination).starts_with(prefix));
+        self.save();
+        Ok(count)
+    }
+
+    /// Deploy the built files of this manifest to `environment`, invalidating
+    /// them, and save and return them as its manifest.
+    ///
+    /// With `rewrite_urls`, text files have this environment's root url replaced
+    /// with the target's, and are written to "{build_directory}-{environment}".
+    #[allow(clippy::too_many_arguments)]
+    pub async fn promote<S: ObjectStore, C: CacheInvalidator>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        invalidator: &C,
+        environment: Environment,
+        rewrite_urls: bool,
+        commit: Option<&str>,
+    ) -> Result<SiteManifest, Error> {
+        snafu::ensure!(
+            environment != self.environment,
+            PromoteToSelfSnafu { environment }
+        );
+        check_built_files(self.files.values())?;
+        let mut promoted = SiteManifest {
+            environment,
+            build_directory: self.build_directory.clone(),
+            render_hash: None,
+            files: self.files.clone(),
+            format: self.format,
+        };
+        let from_url = (cfg.root_url)(&self.environment);
+        let to_url = (cfg.root_url)(&promoted.environment);
+        if rewrite_urls && !from_url.is_empty() && from_url != to_url {
+            promoted.build_directory = format!(
+                "{}-{}",
+                self.build_directory.display(),
+                promoted.environment
+            )
+            .into();
+            log::info!(
+                "rewriting '{from_url}' to '{to_url}' into '{}'",
+                promoted.build_directory.display()
+            );
+            for mf in promoted.files.values_mut() {
+                let content_type = mf
+                    .content_type
+                    .clone()
+                    .unwrap_or_else(|| content_type(&mf.built_filepath));
+                if !is_text(&content_type) {
+                    continue;
+                }
+                let text = std::fs::read_to_string(&mf.built_filepath).context(ReadBuiltSnafu {
+                    path: &mf.built_filepath,
+                })?;
+                if !text.contains(&from_url) {
+                    continue;
+                }
+                let bytes = text.replace(&from_url, &to_url).into_bytes();
+                let built_filepath = promoted.build_directory.join(&mf.destination);
+                let write = WriteFileSnafu {
+                    path: &built_filepath,
+                };
+                if let Some(parent) = built_filepath.parent() {
+                    std::fs::create_dir_all(parent).context(write)?;
+                }
+                mf.content_hash = content_hash(&bytes);
+                std::fs::write(&built_filepath, bytes).context(write)?;
+                mf.built_filepath = built_filepath;
+            }
+        }
+
+        log::info!(
+            "promoting {} files from '{}' to '{}'",
+            promoted.files.len(),
+            self.environment,
+            promoted.environment
+        );
+        promoted
+            .publish(cfg, store, invalidator, promoted.files.values(), commit)
+            .await?;
+        promoted.save();
+        Ok(promoted)
+    }
+
+    /// The file the website configuration is stashed in while in maintenance.
+    fn website_stash_path(&self) -> std::path::PathBuf {
+        format!("{}.{WEBSITE_STASH_EXTENSION}", self.environment).into()
+    }
+
+    /// Turn maintenance on or off, invalidating every path.
+    ///
+    /// Turning it on uploads the maintenance `page` and serves it as the index and
+    /// error document, after stashing the previous website configuration, redirects
+    /// included. Turning it off restores the stashed configuration as it was.
+    pub async fn maintenance<S: ObjectStore, C: CacheInvalidator>(
+        &self,
+        cfg: &SiteConfig,
+        store: &S,
+        invalidator: &C,
+        mode: Maintenance,
+        page: &std::path::Path,
+        commit: Option<&str>,
+    ) -> Result<(), Error> {
+        let path = self.website_stash_path();
+        match mode {
+            Maintenance::On => {
+                snafu::ensure!(!path.exists(), InMaintenanceSnafu { path: &path });
+                let previous = store.website().await?;
+                let stash = serde_yaml::to_string(&previous).unwrap();
+                self.upload(
+                    cfg,
+                    store,
+                    page.to_owned(),
+                    MAINTENANCE_KEY.to_owned(),
+                    false,
+                    false,
+                )
+                .await?;
+                std::fs::write(&path, stash).context(StashWebsiteSnafu { path: &path })?;
+                log::info!("serving '{MAINTENANCE_KEY}' from '{}'", store.name());
+                store
+                    .configure_website(Some(&Website {
+                        index_document: Some(MAINTENANCE_KEY.to_owned()),
+                        error_document: Some(MAINTENANCE_KEY.to_owned()),
+                        ..Website::default()
+                    }))
+                    .await?;
+            }
+            Maintenance::Off => {
+                snafu::ensure!(path.exists(), NotInMaintenanceSnafu { path: &path });
+                let stash =
+                    std::fs::read_to_string(&path).context(StashWebsiteSnafu { path: &path })?;
+                let previous: Option<Website> =
+                    serde_yaml::from_str(&stash).context(ParseStashSnafu { path: &path })?;
+                log::info!("restoring the website configuration of '{}'", store.name());
+                store.configure_website(previous.as_ref()).await?;
+                std::fs::remove_file(&path).context(StashWebsiteSnafu { path: &path })?;
+            }
         }
+        self.invalidate(invalidator, vec!["/*".to_owned()], commit, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Roll back to the `previous` manifest.
+    ///
+    /// Re-uploads every built file referenced by `previous`, deletes any objects
+    /// this manifest has that `previous` doesn't, and invalidates both sets.
+    ///
+    /// The built files must be the ones `previous` recorded, so nothing is
+    /// uploaded unless each one's content hash matches: the site has to be built
+    /// at the previous version first.
+    pub async fn rollback<S: ObjectStore, C: CacheInvalidator>(
+        &mut self,
+        cfg: &SiteConfig,
+        store: &S,
+        invalidator: &C,
+        previous: SiteManifest,
+        commit: Option<String>,
+    ) -> Result<(), Error> {
+        snafu::ensure!(
+            previous.environment == self.environment,
+            RollbackEnvironmentSnafu {
+                environment: self.environment.clone(),
+                previous: previous.environment.clone(),
+            }
+        );
+
+        check_built_files(previous.files.values())?;
+        let mismatched = previous
+            .files
+            .values()
+            .filter(|mf| {
+                let built = std::fs::read(&mf.built_filepath)
+                    .map(|bytes| content_hash(&bytes))
+                    .unwrap_or_default();
+                let matches = !mf.content_hash.is_empty() && built == mf.content_hash;
+                if !matches {
+                    log::error!(
+                        "'{}' doesn't match the hash recorded for '{}'",
+                        mf.built_filepath.display(),
+                        mf.origin
+                    );
+                }
+                !matches
+            })
+            .count();
+        snafu::ensure!(mismatched == 0, RollbackBuildSnafu { count: mismatched });
+
+        let previous_destinations = previous
+            .files
+            .values()
+            .map(|mf| &mf.destination)
+            .collect::<std::collections::BTreeSet<_>>();
+        let added = self
+            .files
+            .values()
+            .filter(|mf| !previous_destinations.contains(&mf.destination))
+            .map(|mf| mf.destination.clone())
+            .collect::<Vec<_>>();
+
+        let files = previous.files.values().collect::<Vec<_>>();
+        self.upload_files(cfg, store, &files, None).await?;
+        futures_util::future::try_join_all(
+            added
+                .iter()
+                .map(|destination| self.delete(store, to_s3_key(destination))),
+        )
+        .await?;
+
+        log::info!("done rolling back s3");
+        let paths = previous
+            .files
+            .values()
+            .map(|mf| &mf.destination)
+            .chain(added.iter())
+            .map(|destination| format!("/{}", to_s3_key(destination)))
+            .collect::<Vec<_>>();
+        self.invalidate(invalidator, paths, commit.as_deref(), false)
+            .await?;
+
+        self.files = previous.files;
+        self.save();
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::pop_parent_replace_ext;
+/// Returns a hash of everything an external page is rendered with besides its
+/// content: the renderer, root url, the build options that change rendered
+/// bytes, layout and variables.
+fn render_hash<R: Renderer>(
+    cfg: &SiteConfig,
+    environment: &Environment,
+    options: &BuildOptions,
+    layout: Option<&Layout>,
+    variables: Option<&BTreeMap<&str, String>>,
+) -> String {
+    let rendering = (
+        options.render_options(),
+        options.pretty_urls,
+        &options.base_path,
+        &options.keep_prefix,
+        options.minify.then_some(options.minify_max_size),
+        options.strip_extension,
+        options.interpolate,
+        &options.templates_dir,
+        &options.languages,
+    );
+    let inputs = format!(
+        "{}\n{}\n{rendering:?}\n{layout:?}\n{variables:?}",
+        std::any::type_name::<R>(),
+        (cfg.root_url)(environment)
+    );
+    content_hash(inputs.as_bytes())
+}
 
-    #[test]
-    fn path_sanity() {
-        let path = std::path::PathBuf::from("parent/child/file.ext");
-        let new_path = pop_parent_replace_ext(path, Some("xyz"));
-        assert_eq!(std::path::PathBuf::from("child/file.xyz"), new_path);
+/// Returns the built-in variables replaced by `--interpolate`.
+fn builtin_variables(environment: &Environment) -> BTreeMap<&'static str, String> {
+    // Object tags ask for these once per upload, so the repository is only read once.
+    static SHA: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    let sha = SHA
+        .get_or_init(|| {
+            std::env::var("PUSHA_COMMIT")
+                .ok()
+                .or_else(commit_hash)
+                .unwrap_or_default()
+        })
+        .clone();
+    BTreeMap::from([
+        ("GIT_SHORT_SHA", sha.chars().take(7).collect()),
+        ("GIT_SHA", sha),
+        ("BUILD_TIME", chrono::Utc::now().to_rfc3339()),
+        ("PUSHA_ENVIRONMENT", environment.to_string()),
+    ])
+}
+
+/// Replace `${NAME}` in `content` with the variable `NAME`, or the environment
+/// variable `NAME`, leaving unknown names as they are.
+fn interpolate(content: &str, variables: &BTreeMap<&str, String>) -> String {
+    // Every page and upload is interpolated, so the pattern is only compiled once.
+    static VARIABLE: std::sync::LazyLock<regex::Regex> =
+        std::sync::LazyLock::new(|| regex::Regex::new(r"\$\{(\w+)\}").unwrap());
+    VARIABLE
+        .replace_all(content, |captures: &regex::Captures| {
+            let name = &captures[1];
+            variables
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok())
+                .unwrap_or_else(|| captures[0].to_owned())
+        })
+        .into_owned()
+}
+
+/// Drop the `.html` extension from the local `href`s in `html`, including absolute
+/// ones under `root_url`, keeping any query or fragment.
+fn strip_html_extensions(html: &str, root_url: &str) -> String {
+    static HREF: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
+        regex::Regex::new(r#"(?P<prefix>\bhref\s*=\s*["'])(?P<link>[^"']*)"#).unwrap()
+    });
+    let root_url = root_url.trim_end_matches('/');
+    HREF.replace_all(html, |captures: &regex::Captures| {
+        let link = &captures["link"];
+        let has_scheme = link
+            .split_once(':')
+            .map(|(scheme, _)| !scheme.contains('/'))
+            .unwrap_or_default();
+        let is_local = (!has_scheme && !link.starts_with("//"))
+            || (!root_url.is_empty() && link.starts_with(root_url));
+        if !is_local {
+            return captures[0].to_owned();
+        }
+        let (path, rest) = link.split_at(link.find(['#', '?']).unwrap_or(link.len()));
+        let path = match path.strip_suffix("index.html") {
+            Some("") => "./",
+            Some(dir) if dir.ends_with('/') => dir,
+            _ => path.strip_suffix(".html").unwrap_or(path),
+        };
+        format!("{}{path}{rest}", &captures["prefix"])
+    })
+    .into_owned()
+}
+
+/// Returns `paths` with the directory of each `index.html` added after it, like
+/// "/blog/" for "/blog/index.html", since s3 website hosting serves the same
+/// object for both and the cache keeps them separately.
+fn with_directory_paths(paths: Vec<String>) -> Vec<String> {
+    paths
+        .into_iter()
+        .flat_map(|path| {
+            let directory = path
+                .strip_suffix("index.html")
+                .filter(|directory| directory.ends_with('/'))
+                .map(str::to_owned);
+            std::iter::once(path).chain(directory)
+        })
+        .collect()
+}
+
+/// Returns the keys of the objects stored under `base_path`.
+async fn stored_keys<S: ObjectStore>(
+    store: &S,
+    base_path: &std::path::Path,
+) -> Result<std::collections::BTreeSet<String>, Error> {
+    let prefix = match to_s3_key(base_path) {
+        prefix if prefix.is_empty() => prefix,
+        prefix => format!("{prefix}/"),
+    };
+    Ok(store.list_objects(&prefix).await?.into_iter().collect())
+}
+
+/// What a deploy did, logged when it finishes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct DeploySummary {
+    /// Files uploaded to keys that had no object, or that weren't in the previous
+    /// manifest when the bucket isn't listed.
+    pub new: usize,
+    /// Files uploaded over an existing object.
+    pub changed: usize,
+    /// Files in the manifest that weren't uploaded.
+    pub skipped: usize,
+    /// Orphaned objects deleted.
+    pub deleted: usize,
+    /// Paths invalidated in the cache.
+    pub invalidated: usize,
+}
+
+impl std::fmt::Display for DeploySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "uploaded {} ({} new, {} changed), skipped {}, deleted {}, invalidated {} paths",
+            self.new + self.changed,
+            self.new,
+            self.changed,
+            self.skipped,
+            self.deleted,
+            self.invalidated
+        )
+    }
+}
+
+/// Returns the caller reference of an invalidation of the deploy of `commit` at
+/// `now`, in milliseconds.
+///
+/// cloudfront treats a reference it has seen as a retry of that invalidation, so
+/// it includes the time unless `idempotent`, and there's a commit to identify the
+/// deploy with.
+fn caller_reference(commit: Option<&str>, idempotent: bool, now: i64) -> String {
+    match commit {
+        Some(hash) if idempotent => format!("xtask-{hash}"),
+        Some(hash) => format!("xtask-{hash}-{now}"),
+        None => {
+            if idempotent {
+                log::warn!(
+                    "there is no commit, using a timestamp for the invalidation caller reference"
+                );
+            }
+            format!("xtask-{now}")
+        }
+    }
+}
+
+/// Check that the stored object is the size of the local file it was uploaded
+/// from, logging what was stored.
+async fn verify_upload<S: ObjectStore>(store: &S, object: &PutObject) -> Result<(), Error> {
+    let key = to_s3_key(&object.key);
+    let head = store
+        .head_object(&key)
+        .await?
+        .context(VerifyMissingSnafu { key: &key })?;
+    let content_type = head.content_type.as_deref().unwrap_or("(none)");
+    log::info!(
+        "stored '{key}': {} bytes as {content_type}, cache control '{}'",
+        head.content_length,
+        head.cache_control.as_deref().unwrap_or("(none)")
+    );
+    if content_type != object.content_type {
+        log::warn!(
+            "'{key}' is stored as {content_type}, not {}",
+            object.content_type
+        );
+    }
+    let expected = std::fs::metadata(&object.path)
+        .map(|meta| meta.len())
+        .unwrap_or_default();
+    snafu::ensure!(
+        head.content_length == expected,
+        VerifyLengthSnafu {
+            key,
+            expected,
+            actual: head.content_length
+        }
+    );
+    Ok(())
+}
+
+/// Check that the built file of each of `files` exists, logging the ones that
+/// don't.
+fn check_built_files<'a>(files: impl IntoIterator<Item = &'a ManifestFile>) -> Result<(), Error> {
+    let mut count = 0usize;
+    for mf in files {
+        if !mf.built_filepath.is_file() {
+            log::error!(
+                "built file '{}' for '{}' does not exist",
+                mf.built_filepath.display(),
+                mf.origin
+            );
+            count += 1;
+        }
+    }
+    snafu::ensure!(count == 0, MissingBuiltFilesSnafu { count });
+    Ok(())
+}
+
+/// Returns whether files of `content_type` are text that may contain urls.
+fn is_text(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || ["javascript", "json", "xml"]
+            .iter()
+            .any(|kind| content_type.contains(kind))
+}
+
+/// Returns the hex encoded sha256 of `bytes`.
+fn content_hash(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns the content type to serve the file at `path` with, declaring text
+/// types as UTF-8.
+fn content_type(path: impl AsRef<std::path::Path>) -> String {
+    let mime = new_mime_guess::from_path(path).first_or_octet_stream();
+    let is_text = mime.type_() == "text"
+        || matches!(
+            mime.essence_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/rss+xml"
+                | "application/atom+xml"
+                | "image/svg+xml"
+        );
+    if is_text {
+        format!("{}; charset=utf-8", mime.essence_str())
+    } else {
+        mime.essence_str().to_owned()
+    }
+}
+
+/// Returns the content type of a page rendered to `destination`, which is HTML
+/// unless the destination's extension says otherwise, like "feed.xml".
+fn rendered_content_type(destination: impl AsRef<std::path::Path>) -> String {
+    let destination = destination.as_ref();
+    if destination.extension().is_some() {
+        content_type(destination)
+    } else {
+        content_type("index.html")
+    }
+}
+
+/// Returns the paths, relative to the current directory, of files that changed
+/// between `git_ref` and `HEAD`.
+fn changed_since(git_ref: &str) -> Result<std::collections::BTreeSet<std::path::PathBuf>, Error> {
+    let repo = git2::Repository::discover(".").context(GitSnafu)?;
+    let old = repo
+        .revparse_single(git_ref)
+        .and_then(|object| object.peel_to_tree())
+        .context(GitSnafu)?;
+    let new = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .context(GitSnafu)?;
+    let diff = repo
+        .diff_tree_to_tree(Some(&old), Some(&new), None)
+        .context(GitSnafu)?;
+
+    let workdir = repo
+        .workdir()
+        .and_then(|dir| dir.canonicalize().ok())
+        .unwrap_or_default();
+    let cwd = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .unwrap_or_default();
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path())
+        .filter_map(|path| {
+            workdir
+                .join(path)
+                .strip_prefix(&cwd)
+                .ok()
+                .map(|path| path.to_path_buf())
+        })
+        .collect())
+}
+
+/// Returns the commit hash of `HEAD`, or `None` if this isn't a git repository.
+fn commit_hash() -> Option<String> {
+    let repo = match git2::Repository::discover(".") {
+        Ok(repo) => repo,
+        Err(e) => {
+            log::warn!("could not open the git repository to get the commit hash: {e}");
+            return None;
+        }
+    };
+    let head = repo.head().and_then(|head| head.peel_to_commit());
+    match head {
+        Ok(commit) => Some(commit.id().to_string()),
+        Err(e) => {
+            log::warn!("could not get the commit hash: {e}");
+            None
+        }
+    }
+}
+
+async fn aws_sdk_config(
+    cfg: &SiteConfig,
+    environment: &Environment,
+    aws: &AwsOptions,
+) -> aws_config::SdkConfig {
+    let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Some(profile) = aws.profile.as_deref() {
+        log::info!("using the '{profile}' AWS profile");
+        loader = loader.profile_name(profile);
+    }
+    let config = loader.load().await;
+    let region = (cfg.region)(environment)
+        .map(aws_config::Region::new)
+        .or_else(|| config.region().cloned())
+        .or_else(|| {
+            std::env::var("AWS_DEFAULT_REGION")
+                .ok()
+                .map(aws_config::Region::new)
+        })
+        .unwrap_or_else(|| aws_config::Region::new(DEFAULT_REGION));
+    log::debug!("using the '{region}' AWS region");
+    let mut builder = config.to_builder().region(region);
+    if let Some(seconds) = aws.aws_timeout {
+        builder = builder.timeout_config(
+            aws_config::timeout::TimeoutConfig::builder()
+                .operation_attempt_timeout(std::time::Duration::from_secs(seconds))
+                .build(),
+        );
+    }
+    let config = builder.build();
+    let Some(role_arn) = aws.assume_role_arn.as_deref() else {
+        return config;
+    };
+    log::info!("assuming the AWS role '{role_arn}'");
+    let provider = aws_config::sts::AssumeRoleProvider::builder(role_arn)
+        .session_name(&aws.role_session_name)
+        .configure(&config);
+    let provider = match aws.external_id.as_deref() {
+        Some(external_id) => provider.external_id(external_id),
+        None => provider,
+    };
+    config
+        .to_builder()
+        .credentials_provider(aws_sdk_s3::config::SharedCredentialsProvider::new(
+            provider.build().await,
+        ))
+        .build()
+}
+
+/// The configuration of an environment, as a deploy would use it.
+#[derive(Debug, serde::Serialize)]
+struct ResolvedConfig {
+    environment: Environment,
+    root_url: String,
+    s3_bucket: Option<String>,
+    cloudfront_distro: Vec<String>,
+    region: String,
+    layout: Option<std::path::PathBuf>,
+    storage_class: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    object_tags: BTreeMap<String, String>,
+    profile: Option<String>,
+    assume_role_arn: Option<String>,
+    /// The values given on the command line instead of the site config's.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    overrides: BTreeMap<&'static str, String>,
+}
+
+impl ResolvedConfig {
+    /// Resolve the configuration of `environment`, with the overrides in `aws`.
+    ///
+    /// The region comes from the AWS environment when the site config has none,
+    /// the same way AWS clients are configured.
+    async fn resolve(cfg: &SiteConfig, environment: &Environment, aws: &AwsOptions) -> Self {
+        let region = match (cfg.region)(environment) {
+            Some(region) => region,
+            None => aws_sdk_config(cfg, environment, aws)
+                .await
+                .region()
+                .map(|region| region.to_string())
+                .unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+        };
+        let mut overrides = BTreeMap::new();
+        if let Some(bucket) = aws.bucket.clone() {
+            overrides.insert("bucket", bucket);
+        }
+        if !aws.distributions.is_empty() {
+            overrides.insert("distribution", aws.distributions.join(", "));
+        }
+        if let Some(storage_class) = aws.storage_class.clone() {
+            overrides.insert("storage_class", storage_class);
+        }
+        ResolvedConfig {
+            environment: environment.clone(),
+            root_url: (cfg.root_url)(environment),
+            s3_bucket: aws.bucket(cfg, environment),
+            cloudfront_distro: aws.distributions(cfg, environment),
+            region,
+            layout: (cfg.layout)(environment),
+            storage_class: aws.storage_class(cfg, environment),
+            object_tags: (cfg.object_tags)(environment),
+            profile: aws.profile.clone(),
+            assume_role_arn: aws.assume_role_arn.clone(),
+            overrides,
+        }
+    }
+}
+
+/// Returns the given cloudfront distributions separated by commas, or "(none)".
+fn distribution_names(distributions: &[String]) -> String {
+    if distributions.is_empty() {
+        "(none)".to_owned()
+    } else {
+        distributions.join(", ")
+    }
+}
+
+/// Ask on the terminal for confirmation to `action` (like "deploy to") `environment`,
+/// by typing "yes" or the name of its bucket.
+fn confirm(
+    cfg: &SiteConfig,
+    aws: &AwsOptions,
+    action: &'static str,
+    environment: &Environment,
+) -> Result<(), Error> {
+    let bucket = aws.bucket(cfg, environment);
+    let distributions = aws.distributions(cfg, environment);
+    eprintln!("You are about to {action} '{environment}':");
+    eprintln!("  s3 bucket: {}", bucket.as_deref().unwrap_or("(none)"));
+    eprintln!(
+        "  cloudfront distribution: {}",
+        distribution_names(&distributions)
+    );
+    eprint!("Type 'yes' or the bucket name to continue: ");
+    std::io::stderr().flush().unwrap();
+
+    let mut answer = String::new();
+    // A closed stdin reads as an empty answer, which is not a confirmation.
+    let _ = std::io::stdin().read_line(&mut answer);
+    let answer = answer.trim();
+    let confirmed = answer == "yes" || Some(answer) == bucket.as_deref();
+    snafu::ensure!(
+        confirmed,
+        NotConfirmedSnafu {
+            action,
+            environment: environment.clone()
+        }
+    );
+    Ok(())
+}
+
+/// Build the site for `environment` into `build_directory`, saving and returning
+/// its manifest.
+pub async fn build_site<R: Renderer>(
+    cfg: &SiteConfig,
+    environment: Environment,
+    build_directory: impl Into<std::path::PathBuf>,
+    options: &BuildOptions,
+    external_pages: impl IntoIterator<Item = ExternalPage>,
+) -> Result<SiteManifest, Error> {
+    let mut manifest =
+        SiteManifest::open(environment, build_directory.into(), options.manifest_format)?;
+    manifest.build::<R>(cfg, options, external_pages).await?;
+    manifest.save();
+    Ok(manifest)
+}
+
+/// Build the site for `environment` into `build_directory` and deploy it,
+/// returning its manifest.
+///
+/// If `commit` is `None`, the commit hash is read from the git repository in the
+/// current directory.
+pub async fn deploy_site<R: Renderer>(
+    cfg: &SiteConfig,
+    environment: Environment,
+    build_directory: impl Into<std::path::PathBuf>,
+    build_options: &BuildOptions,
+    options: &DeployOptions,
+    external_pages: impl IntoIterator<Item = ExternalPage>,
+    commit: Option<String>,
+) -> Result<SiteManifest, Error> {
+    let mut manifest = SiteManifest::open(
+        environment,
+        build_directory.into(),
+        build_options.manifest_format,
+    )?;
+    let commit = commit.or_else(commit_hash);
+    let in_flight = options.aws.in_flight();
+    let store = S3Store::from_config(cfg, &manifest.environment, &options.aws)
+        .await?
+        .with_multipart(options.multipart)
+        .with_in_flight(in_flight.clone());
+    let invalidator =
+        CloudFrontInvalidator::maybe_from_config(cfg, &manifest.environment, &options.aws)
+            .await?
+            .map(|invalidator| invalidator.with_in_flight(in_flight));
+    manifest
+        .deploy::<R, _, _>(
+            cfg,
+            &store,
+            &invalidator,
+            build_options,
+            options,
+            external_pages,
+            commit,
+        )
+        .await?;
+    Ok(manifest)
+}
+
+/// Read the manifest of `environment` from `{environment}.yaml`, or the extension
+/// of `format`, returning its path too.
+fn read_manifest(
+    environment: &Environment,
+    format: Option<ManifestFormat>,
+) -> Result<(std::path::PathBuf, SiteManifest), Error> {
+    let path = ManifestFormat::path(environment, format);
+    let contents = std::fs::read(&path).ok().context(NoManifestSnafu {
+        environment: environment.clone(),
+    })?;
+    let manifest = ManifestFormat::of(&path)
+        .parse(&contents)
+        .context(ParseManifestSnafu { path: &path })?;
+    Ok((path, manifest))
+}
+
+/// Load the `KEY=value` lines of the dotenv file at `path` into the process
+/// environment, replacing variables that are already set only if `overwrite`.
+fn load_env_file(path: &std::path::Path, overwrite: bool) -> Result<(), Error> {
+    log::info!("loading environment variables from '{}'", path.display());
+    if overwrite {
+        dotenvy::from_path_override(path)
+    } else {
+        dotenvy::from_path(path)
+    }
+    .context(EnvFileSnafu { path })
+}
+
+/// Run pusha as a command line tool, parsing the command from the process arguments.
+pub async fn run<R: Renderer>(
+    cfg: &SiteConfig,
+    external_pages: impl IntoIterator<Item = ExternalPage>,
+) {
+    let mut cli = Cli::parse();
+    cli.log_format.init(&cli.environment, cli.cmd.name());
+    if let Some(path) = cli.env_file.as_ref() {
+        if let Err(e) = load_env_file(path, cli.env_file_override) {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+        // Parse again so options read from the environment see the loaded variables.
+        cli = Cli::parse();
+    }
+
+    let file_cfg = match cli.config.as_ref().map(SiteConfig::from_file).transpose() {
+        Ok(file_cfg) => file_cfg,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let cfg = file_cfg.as_ref().unwrap_or(cfg);
+
+    let fail_on_warning = cli.fail_on_warning;
+    if let Err(e) = run_command::<R>(cfg, cli, external_pages).await {
+        log::error!("{e}");
+        std::process::exit(1);
+    }
+    let warnings = WARNINGS.load(std::sync::atomic::Ordering::Relaxed);
+    if fail_on_warning && warnings > 0 {
+        log::error!("{warnings} warnings were logged, failing because of --fail-on-warning");
+        std::process::exit(1);
+    }
+}
+
+async fn run_command<R: Renderer>(
+    cfg: &SiteConfig,
+    cli: Cli,
+    external_pages: impl IntoIterator<Item = ExternalPage>,
+) -> Result<(), Error> {
+    let load_manifest = || {
+        SiteManifest::open(
+            cli.environment.clone(),
+            cli.build_directory.clone().into(),
+            cli.manifest_format,
+        )
+    };
+    let mut external_pages = external_pages.into_iter().collect::<Vec<_>>();
+    if let Some(path) = cli.external_pages.as_ref() {
+        external_pages.extend(ExternalPage::read_list(path)?);
+    }
+
+    let with_overrides =
+        |aws: AwsOptions| aws.with_overrides(cli.bucket.clone(), cli.distribution.clone());
+    match cli.cmd {
+        Command::Deploy {
+            mut build,
+            mut options,
+        } => {
+            build.manifest_format = cli.manifest_format;
+            options.aws = with_overrides(options.aws);
+            if cli.environment == Environment::Production && !options.yes {
+                confirm(cfg, &options.aws, "deploy to", &cli.environment)?;
+            }
+            let manifest = deploy_site::<R>(
+                cfg,
+                cli.environment.clone(),
+                cli.build_directory.clone(),
+                &build,
+                &options,
+                external_pages,
+                cli.commit,
+            )
+            .await?;
+            log::debug!("manifest: {manifest:#?}");
+            cli.output_format.print(&manifest);
+        }
+        Command::Build { mut options } => {
+            options.manifest_format = cli.manifest_format;
+            let manifest = build_site::<R>(
+                cfg,
+                cli.environment.clone(),
+                cli.build_directory.clone(),
+                &options,
+                external_pages,
+            )
+            .await?;
+            cli.output_format.print(&manifest);
+        }
+        Command::Diff { options } => {
+            let manifest = load_manifest()?;
+            let build_directory =
+                std::env::temp_dir().join(format!("pusha-diff-{}", std::process::id()));
+            let mut fresh = SiteManifest {
+                environment: manifest.environment.clone(),
+                build_directory: build_directory.clone(),
+                ..Default::default()
+            };
+            // Only the built files are compared, so nothing outside of the
+            // temporary build directory is touched.
+            let options = BuildOptions {
+                pre_build_cmd: None,
+                post_build_cmd: None,
+                timings: None,
+                preload_map: None,
+                ..options
+            };
+            let result = fresh.build::<R>(cfg, &options, external_pages).await;
+            let diff = manifest.diff(&fresh);
+            if let Err(e) = std::fs::remove_dir_all(&build_directory) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("could not remove '{}': {e}", build_directory.display());
+                }
+            }
+            result?;
+            print!("{diff}");
+        }
+        Command::Clean => load_manifest()?.clean(),
+        Command::Init => {
+            init::init(".")?;
+        }
+        Command::Upload {
+            path,
+            key,
+            create_only,
+            verify,
+            multipart,
+            aws,
+        } => {
+            let aws = with_overrides(aws);
+            let key = key.unwrap_or_else(|| {
+                let filename = path.file_name().unwrap().to_string_lossy().to_string();
+                format!(
+                    "{UPLOADS_PREFIX}{}",
+                    filename
+                        .replace(" ", "_")
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .concat()
+                )
+            });
+            let store = S3Store::from_config(cfg, &cli.environment, &aws)
+                .await?
+                .with_multipart(multipart)
+                .with_in_flight(aws.in_flight());
+            load_manifest()?
+                .upload(cfg, &store, path, key, create_only, verify)
+                .await?;
+        }
+        Command::Check => {
+            let manifest = load_manifest()?;
+            let destinations = manifest
+                .files
+                .values()
+                .map(|mf| mf.destination.clone())
+                .collect();
+            let broken = check::find_broken_links(
+                &manifest.build_directory,
+                &destinations,
+                &(cfg.root_url)(&manifest.environment),
+            )?;
+            for link in broken.iter() {
+                log::error!("broken link '{}' in '{}'", link.link, link.source.display());
+            }
+            snafu::ensure!(
+                broken.is_empty(),
+                BrokenLinksSnafu {
+                    count: broken.len()
+                }
+            );
+            log::info!("no broken links found");
+        }
+        Command::Validate => {
+            let (path, manifest) = read_manifest(&cli.environment, cli.manifest_format)?;
+            let problems = manifest.problems();
+            for problem in problems.iter() {
+                log::error!("{problem}");
+            }
+            snafu::ensure!(
+                problems.is_empty(),
+                InvalidManifestSnafu {
+                    count: problems.len()
+                }
+            );
+            log::info!("'{}' is valid", path.display());
+        }
+        Command::Rollback {
+            manifest: previous_path,
+            git_ref,
+            aws,
+        } => {
+            let aws = with_overrides(aws);
+            let current_path = ManifestFormat::path(&cli.environment, cli.manifest_format);
+            let previous_path = previous_path.unwrap_or_else(|| current_path.clone());
+            let is_current = previous_path == current_path
+                || matches!(
+                    (previous_path.canonicalize(), current_path.canonicalize()),
+                    (Ok(previous), Ok(current)) if previous == current
+                );
+            snafu::ensure!(
+                git_ref.is_some() || !is_current,
+                RollbackToCurrentSnafu {
+                    path: &previous_path
+                }
+            );
+            let previous = SiteManifest::read(previous_path, git_ref.as_deref())?;
+            let commit = cli.commit.clone().or_else(commit_hash);
+            let in_flight = aws.in_flight();
+            let store = S3Store::from_config(cfg, &cli.environment, &aws)
+                .await?
+                .with_in_flight(in_flight.clone());
+            let invalidator = CloudFrontInvalidator::maybe_from_config(cfg, &cli.environment, &aws)
+                .await?
+                .map(|invalidator| invalidator.with_in_flight(in_flight));
+            load_manifest()?
+                .rollback(cfg, &store, &invalidator, previous, commit)
+                .await?;
+        }
+        Command::Purge { prefix, yes, aws } => {
+            let aws = with_overrides(aws);
+            if cli.environment == Environment::Production && !yes {
+                return NotConfirmedSnafu {
+                    action: "purge",
+                    environment: cli.environment.clone(),
+                }
+                .fail();
+            }
+            if !yes {
+                confirm(cfg, &aws, "purge", &cli.environment)?;
+            }
+            let store = S3Store::from_config(cfg, &cli.environment, &aws)
+                .await?
+                .with_in_flight(aws.in_flight());
+            let prefix = prefix
+                .as_deref()
+                .unwrap_or_default()
+                .trim_start_matches('/');
+            load_manifest()?.purge(&store, prefix).await?;
+        }
+        Command::Render {
+            extra_classes,
+            heading_anchors,
+            toc,
+        } => {
+            let mut content = String::new();
+            std::io::stdin()
+                .read_to_string(&mut content)
+                .context(ReadStdinSnafu)?;
+            let options = RenderOptions {
+                heading_anchors,
+                toc,
+            }
+            .with_front_matter(&content);
+            let html = R::render_content_with_options(
+                cfg,
+                &cli.environment,
+                content,
+                &extra_classes,
+                &options,
+            )
+            .map_err(|e| Error::Render {
+                message: e.to_string(),
+            })?;
+            print!("{html}");
+        }
+        Command::Config { aws } => {
+            let aws = with_overrides(aws);
+            let resolved = ResolvedConfig::resolve(cfg, &cli.environment, &aws).await;
+            match cli.output_format {
+                OutputFormat::None => OutputFormat::Yaml,
+                format => format,
+            }
+            .print(&resolved);
+        }
+        Command::Promote {
+            from,
+            to,
+            rewrite_urls,
+            yes,
+            aws,
+        } => {
+            let aws = with_overrides(aws);
+            snafu::ensure!(from != to, PromoteToSelfSnafu { environment: to });
+            if to == Environment::Production && !yes {
+                confirm(cfg, &aws, "promote to", &to)?;
+            }
+            let (_, manifest) = read_manifest(&from, cli.manifest_format)?;
+            let commit = cli.commit.clone().or_else(commit_hash);
+            let in_flight = aws.in_flight();
+            let store = S3Store::from_config(cfg, &to, &aws)
+                .await?
+                .with_in_flight(in_flight.clone());
+            let invalidator = CloudFrontInvalidator::maybe_from_config(cfg, &to, &aws)
+                .await?
+                .map(|invalidator| invalidator.with_in_flight(in_flight));
+            let promoted = manifest
+                .promote(
+                    cfg,
+                    &store,
+                    &invalidator,
+                    to,
+                    rewrite_urls,
+                    commit.as_deref(),
+                )
+                .await?;
+            cli.output_format.print(&promoted);
+        }
+        Command::Maintenance {
+            mode,
+            page,
+            yes,
+            aws,
+        } => {
+            let aws = with_overrides(aws);
+            if cli.environment == Environment::Production && !yes {
+                confirm(
+                    cfg,
+                    &aws,
+                    "change the maintenance mode of",
+                    &cli.environment,
+                )?;
+            }
+            let commit = cli.commit.clone().or_else(commit_hash);
+            let in_flight = aws.in_flight();
+            let store = S3Store::from_config(cfg, &cli.environment, &aws)
+                .await?
+                .with_in_flight(in_flight.clone());
+            let invalidator = CloudFrontInvalidator::maybe_from_config(cfg, &cli.environment, &aws)
+                .await?
+                .map(|invalidator| invalidator.with_in_flight(in_flight));
+            load_manifest()?
+                .maintenance(cfg, &store, &invalidator, mode, &page, commit.as_deref())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        absolute_links, builtin_variables, caller_reference, content_hash, content_type,
+        decode_page, get_content_files, get_files, interpolate, is_manifest_file, load_env_file,
+        markdown_destination, page_language, page_url, parse_bucket, parse_storage_class,
+        pop_parent_replace_ext, render_error, rendered_content_type, request_headers, stored_keys,
+        strip_html_extensions, to_s3_key, with_directory_paths, AwsOptions, BuildFailures,
+        BuildOptions, Compression, DeployOptions, DeploySummary, Environment, ExternalPage,
+        FileKind, Layout, Maintenance, ManifestDiff, ManifestFile, ManifestFormat,
+        MemoryInvalidator, MemoryStore, ObjectStore, PageContext, PageSource, PutObject,
+        RenderOptions, Renderer, ResolvedConfig, SiteConfig, SiteManifest, Website,
+    };
+
+    #[test]
+    fn path_sanity() {
+        let path = std::path::PathBuf::from("parent/child/file.ext");
+        let new_path = pop_parent_replace_ext(path, Some("xyz"));
+        assert_eq!(std::path::PathBuf::from("child/file.xyz"), new_path);
+    }
+
+    #[test]
+    fn cli() {
+        <crate::Cli as clap::CommandFactory>::command().debug_assert();
+    }
+
+    #[test]
+    fn index_destinations() {
+        for pretty_urls in [false, true] {
+            let dest = |path: &str| markdown_destination(path, pretty_urls);
+            assert_eq!(
+                std::path::PathBuf::from("index.html"),
+                dest("content/index.md")
+            );
+            assert_eq!(
+                std::path::PathBuf::from("index.html"),
+                dest("./content/index.md")
+            );
+            assert_eq!(
+                std::path::PathBuf::from("index.html"),
+                dest("content.staging/index.md")
+            );
+            assert_eq!(std::path::PathBuf::from("index.html"), dest("index.md"));
+            assert_eq!(
+                std::path::PathBuf::from("blog/index.html"),
+                dest("content/blog/index.md")
+            );
+            assert_eq!(
+                std::path::PathBuf::from("blog/2024/index.html"),
+                dest("content/blog/2024/index.md")
+            );
+            assert_eq!(std::path::PathBuf::from("404.html"), dest("content/404.md"));
+        }
+    }
+
+    #[test]
+    fn pretty_urls() {
+        let dest = |path: &str| markdown_destination(path, true);
+        assert_eq!(
+            std::path::PathBuf::from("post/index.html"),
+            dest("content/post.md")
+        );
+        assert_eq!(
+            std::path::PathBuf::from("blog/post/index.html"),
+            dest("content/blog/post.md")
+        );
+        assert_eq!(
+            std::path::PathBuf::from("blog/index.html"),
+            dest("content/blog/index.md")
+        );
+        assert_eq!(
+            std::path::PathBuf::from("post.html"),
+            markdown_destination("content/post.md", false)
+        );
+    }
+
+    #[test]
+    fn environment_round_trip() {
+        for env in [
+            Environment::Local,
+            Environment::Production,
+            Environment::Custom("pr-123".to_owned()),
+        ] {
+            assert_eq!(env, env.to_string().parse().unwrap());
+            let yaml = serde_yaml::to_string(&env).unwrap();
+            assert_eq!(env, serde_yaml::from_str::<Environment>(&yaml).unwrap());
+        }
+        assert_eq!(Environment::Staging, "Staging".parse().unwrap());
+        assert!("not/valid".parse::<Environment>().is_err());
+    }
+
+    #[test]
+    fn manifest_diff() {
+        let manifest = |files: &[(&str, i64, &str)]| SiteManifest {
+            files: files
+                .iter()
+                .map(|(origin, modified, hash)| {
+                    let mfile = ManifestFile {
+                        origin: origin.to_string(),
+                        origin_modified: chrono::DateTime::from_timestamp(*modified, 0)
+                            .unwrap()
+                            .fixed_offset(),
+                        built_filepath: format!("site/{origin}").into(),
+                        destination: origin.into(),
+                        content_type: None,
+                        kind: None,
+                        content_hash: hash.to_string(),
+                        source_hash: None,
+                    };
+                    (origin.to_string(), mfile)
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let old = manifest(&[("a", 0, ""), ("b", 0, ""), ("c", 0, ""), ("e", 0, "1")]);
+        let new = manifest(&[("a", 0, ""), ("b", 1, ""), ("d", 0, ""), ("e", 1, "1")]);
+        assert_eq!(
+            ManifestDiff {
+                added: vec!["d".to_owned()],
+                removed: vec!["c".to_owned()],
+                modified: vec!["b".to_owned()],
+            },
+            old.diff(&new)
+        );
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            content_hash(b"")
+        );
+    }
+
+    #[test]
+    fn manifest_problems() {
+        let built = std::env::temp_dir().join(format!("pusha-validate-{}", std::process::id()));
+        std::fs::write(&built, "").unwrap();
+        let file = |destination: &str, built_filepath: &std::path::Path| ManifestFile {
+            origin: destination.to_owned(),
+            origin_modified: Default::default(),
+            built_filepath: built_filepath.to_owned(),
+            destination: destination.into(),
+            content_type: None,
+            kind: None,
+            content_hash: String::new(),
+            source_hash: None,
+        };
+        let mut manifest = SiteManifest::default();
+        manifest
+            .files
+            .insert("a".to_owned(), file("blog/a.html", &built));
+        assert!(manifest.problems().is_empty());
+        manifest
+            .files
+            .insert("b".to_owned(), file("blog/a.html", &built));
+        manifest
+            .files
+            .insert("c".to_owned(), file("/c.html", &built));
+        manifest
+            .files
+            .insert("d".to_owned(), file("d.html", "missing.html".as_ref()));
+        assert_eq!(3, manifest.problems().len());
+        std::fs::remove_file(&built).unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_to_memory() {
+        let dir = std::env::temp_dir().join(format!("pusha-publish-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("blog")).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>home</p>").unwrap();
+        std::fs::write(dir.join("blog/post.html"), "<p>post</p>").unwrap();
+
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .cache_control(|environment, _| {
+                (environment == &Environment::Staging).then(|| "no-store".to_owned())
+            })
+            .object_tags(|_| {
+                BTreeMap::from([("environment".to_owned(), "${PUSHA_ENVIRONMENT}".to_owned())])
+            })
+            .build();
+        let manifest = SiteManifest {
+            files: ["index.html", "blog/post.html"]
+                .into_iter()
+                .map(|path| {
+                    let mfile = ManifestFile {
+                        origin: path.to_owned(),
+                        origin_modified: chrono::Utc::now().fixed_offset(),
+                        built_filepath: dir.join(path),
+                        destination: path.into(),
+                        content_type: None,
+                        kind: None,
+                        content_hash: content_hash(&std::fs::read(dir.join(path)).unwrap()),
+                        source_hash: None,
+                    };
+                    (path.to_owned(), mfile)
+                })
+                .collect(),
+            environment: Environment::Staging,
+            ..Default::default()
+        };
+        let store = MemoryStore::default();
+        let invalidator = MemoryInvalidator::default();
+        manifest
+            .publish(&cfg, &store, &invalidator, manifest.files.values(), None)
+            .await
+            .unwrap();
+
+        let objects = store.objects();
+        assert_eq!(b"<p>post</p>".to_vec(), objects["blog/post.html"].body);
+        assert_eq!("staging", objects["blog/post.html"].tags["environment"]);
+        let mut files = manifest.files.values().collect::<Vec<_>>();
+        assert!(manifest
+            .changed_files(&store, files.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        let changed = ManifestFile {
+            content_hash: "changed".to_owned(),
+            ..manifest.files["index.html"].clone()
+        };
+        files.push(&changed);
+        assert_eq!(
+            1,
+            manifest.changed_files(&store, files).await.unwrap().len()
+        );
+        let missing = ManifestFile {
+            built_filepath: dir.join("missing.html"),
+            destination: "missing.html".into(),
+            ..manifest.files["index.html"].clone()
+        };
+        assert!(manifest
+            .publish(&cfg, &store, &invalidator, [&missing], None)
+            .await
+            .is_err());
+        assert!(!store.objects().contains_key("missing.html"));
+        let upload = |key: &str| {
+            manifest.upload(
+                &cfg,
+                &store,
+                dir.join("index.html"),
+                key.to_owned(),
+                true,
+                true,
+            )
+        };
+        assert!(upload("index.html").await.is_err());
+        upload("uploads/index.html").await.unwrap();
+        manifest
+            .publish(&cfg, &store, &invalidator, [], None)
+            .await
+            .unwrap();
+        manifest
+            .publish(
+                &cfg,
+                &store,
+                &None::<MemoryInvalidator>,
+                [&manifest.files["index.html"]],
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            "text/html; charset=utf-8",
+            objects["index.html"].content_type
+        );
+        assert_eq!(
+            Some("no-store".to_owned()),
+            objects["index.html"].cache_control
+        );
+        assert_eq!(
+            vec![vec![
+                "/blog/post.html".to_owned(),
+                "/index.html".to_owned(),
+                "/".to_owned()
+            ]],
+            invalidator.invalidations()
+        );
+
+        for key in ["stale.html", "uploads/logo.png"] {
+            store
+                .put_object(PutObject {
+                    key: key.to_owned(),
+                    path: dir.join("index.html"),
+                    content_type: "text/html".to_owned(),
+                    cache_control: None,
+                    content_encoding: None,
+                    tags: BTreeMap::new(),
+                    content_hash: None,
+                    create_only: false,
+                })
+                .await
+                .unwrap();
+        }
+        let base_path = std::path::Path::new("");
+        let dry_run = DeployOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let deleted = manifest
+            .delete_orphans(
+                &cfg,
+                &store,
+                stored_keys(&store, base_path).await.unwrap(),
+                &dry_run,
+            )
+            .await
+            .unwrap();
+        assert!(deleted.is_empty());
+        assert_eq!(5, store.objects().len());
+        let deleted = manifest
+            .delete_orphans(
+                &cfg,
+                &store,
+                stored_keys(&store, base_path).await.unwrap(),
+                &DeployOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec!["stale.html".to_owned()], deleted);
+        assert!(store.objects().contains_key("uploads/logo.png"));
+
+        store.delete_object("index.html").await.unwrap();
+        assert_eq!(3, store.objects().len());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn s3_keys() {
+        assert_eq!("blog/post.html", to_s3_key("blog/post.html"));
+        assert_eq!("blog/post.html", to_s3_key("blog\\post.html"));
+        assert_eq!("blog/post.html", to_s3_key("\\blog\\post.html"));
+        assert_eq!("blog/post.html", to_s3_key("/blog/post.html"));
+        assert_eq!("blog/post.html", to_s3_key("./blog/./post.html"));
+        assert_eq!("post.html", to_s3_key("blog\\..\\post.html"));
+        assert_eq!("post.html", to_s3_key("../post.html"));
+        assert_eq!("uploads/a_b.png", to_s3_key("uploads//a_b.png"));
+    }
+
+    #[test]
+    fn text_content_types() {
+        assert_eq!("text/html; charset=utf-8", content_type("index.html"));
+        assert_eq!("image/svg+xml; charset=utf-8", content_type("logo.svg"));
+        assert_eq!("image/png", content_type("logo.png"));
+        assert_eq!("text/html; charset=utf-8", rendered_content_type("devlog"));
+        assert_eq!(
+            "application/xml; charset=utf-8",
+            rendered_content_type("feed.xml")
+        );
+    }
+
+    #[test]
+    fn layout_placeholders() {
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .build();
+        let environment = Environment::Staging;
+        let destination = std::path::Path::new("blog/post.html");
+        let page = PageContext::new(
+            &cfg,
+            &environment,
+            destination,
+            "",
+            RenderOptions::default(),
+        );
+        let layout = Layout(
+            "<base href=\"{{root_url}}\"><main data-env=\"{{ environment }}\">{{ content }}</main>{{ other }}"
+                .to_owned(),
+        );
+        assert_eq!(
+            "<base href=\"https://example.com\"><main data-env=\"staging\"><p>hi</p></main>{{ other }}",
+            layout.wrap(&cfg, &page, "<p>hi</p>")
+        );
+    }
+
+    #[test]
+    fn env_file() {
+        let path = std::env::temp_dir().join(format!("pusha-{}.env", std::process::id()));
+        std::fs::write(
+            &path,
+            "PUSHA_TEST_ENV_FILE_NEW=loaded\nPUSHA_TEST_ENV_FILE_SET=loaded\n",
+        )
+        .unwrap();
+        std::env::set_var("PUSHA_TEST_ENV_FILE_SET", "shell");
+        load_env_file(&path, false).unwrap();
+        assert_eq!("loaded", std::env::var("PUSHA_TEST_ENV_FILE_NEW").unwrap());
+        assert_eq!("shell", std::env::var("PUSHA_TEST_ENV_FILE_SET").unwrap());
+        load_env_file(&path, true).unwrap();
+        assert_eq!("loaded", std::env::var("PUSHA_TEST_ENV_FILE_SET").unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_env_file(&path, false).is_err());
+    }
+
+    #[test]
+    fn base_paths() {
+        let options = |base_path: Option<&str>| BuildOptions {
+            base_path: base_path.map(str::to_owned),
+            ..Default::default()
+        };
+        assert_eq!(std::path::PathBuf::new(), options(None).base_path());
+        assert_eq!("", options(Some("/")).base_url_path());
+        assert_eq!(
+            std::path::PathBuf::from("docs/v1"),
+            options(Some("/docs/v1/")).base_path()
+        );
+        assert_eq!("/docs/v1", options(Some("docs/v1")).base_url_path());
+        let options = BuildOptions {
+            keep_prefix: Some("/app/".to_owned()),
+            ..options(Some("/docs"))
+        };
+        assert_eq!(std::path::PathBuf::from("docs/app"), options.content_path());
+        assert_eq!("/docs", options.base_url_path());
+    }
+
+    #[test]
+    fn page_languages() {
+        let languages = ["en".to_owned(), "fr".to_owned()];
+        assert_eq!(
+            Some(("fr", std::path::PathBuf::from("blog/index.html"))),
+            page_language(&languages, std::path::Path::new("fr/blog/index.html"))
+        );
+        assert_eq!(
+            None,
+            page_language(&languages, std::path::Path::new("de/index.html"))
+        );
+        assert_eq!(
+            None,
+            page_language(&[], std::path::Path::new("en/index.html"))
+        );
+    }
+
+    #[test]
+    fn remote_headers() {
+        std::env::set_var("PUSHA_TEST_TOKEN", "secret");
+        let headers = [(
+            "Authorization".to_owned(),
+            "Bearer ${PUSHA_TEST_TOKEN}".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+        let map = request_headers("page", &headers).unwrap();
+        assert_eq!("Bearer secret", map["authorization"]);
+
+        let headers = [("X-Token".to_owned(), "${PUSHA_TEST_UNSET}".to_owned())]
+            .into_iter()
+            .collect();
+        assert!(request_headers("page", &headers).is_err());
+    }
+
+    #[test]
+    fn external_links() {
+        let base = reqwest::Url::parse("https://example.com/blog/post.md").unwrap();
+        let content = "![cat](images/cat.png) [home](/) [top](#top) \
+                       [elsewhere](https://example.org/x) [mail](mailto:a@b.c)\n\
+                       [ref]: ../about.md\n\
+                       <img src=\"cat.png\"> <a href='//cdn.example.com/a.js'>a</a>";
+        assert_eq!(
+            "![cat](https://example.com/blog/images/cat.png) [home](https://example.com/) \
+             [top](#top) [elsewhere](https://example.org/x) [mail](mailto:a@b.c)\n\
+             [ref]: https://example.com/about.md\n\
+             <img src=\"https://example.com/blog/cat.png\"> \
+             <a href='//cdn.example.com/a.js'>a</a>",
+            absolute_links(&base, content)
+        );
+    }
+
+    #[test]
+    fn stripped_extensions() {
+        let html = "<a href=\"/about.html\">a</a> <a href='blog/index.html#top'>b</a> \
+                    <a href=\"https://example.com/c.html?x=1\">c</a> \
+                    <a href=\"https://example.org/d.html\">d</a> <a href=\"index.html\">e</a> \
+                    <img src=\"e.html\">";
+        assert_eq!(
+            "<a href=\"/about\">a</a> <a href='blog/#top'>b</a> \
+             <a href=\"https://example.com/c?x=1\">c</a> \
+             <a href=\"https://example.org/d.html\">d</a> <a href=\"./\">e</a> \
+             <img src=\"e.html\">",
+            strip_html_extensions(html, "https://example.com/")
+        );
+    }
+
+    #[test]
+    fn bucket_names() {
+        assert!(parse_bucket("my-site.example.com").is_ok());
+        assert!(parse_bucket("ab").is_err());
+        assert!(parse_bucket("My_Bucket").is_err());
+        assert!(parse_bucket("-site").is_err());
+        assert!(parse_bucket("my..site").is_err());
+    }
+
+    #[test]
+    fn storage_classes() {
+        assert_eq!(
+            Ok("STANDARD_IA".to_owned()),
+            parse_storage_class("STANDARD_IA")
+        );
+        assert!(parse_storage_class("CHEAP").is_err());
+    }
+
+    #[test]
+    fn decode_pages() {
+        assert_eq!(
+            "caf\u{FFFD}",
+            decode_page("page", Some("text/markdown"), b"caf\xe9".to_vec()).unwrap()
+        );
+        assert_eq!("café", decode_page("page", None, "café".into()).unwrap());
+        assert!(decode_page("page", Some("image/png"), b"png".to_vec()).is_err());
+        assert!(decode_page("page", None, b"\x89PNG\0\0".to_vec()).is_err());
+    }
+
+    #[test]
+    fn robots_txt() {
+        let dir = std::env::temp_dir().join(format!("pusha-robots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .build();
+        let robots = |environment: Environment| {
+            let mut manifest = SiteManifest {
+                environment,
+                build_directory: dir.clone(),
+                ..Default::default()
+            };
+            manifest.write_robots_txt(&cfg);
+            std::fs::read_to_string(&manifest.files["robots.txt"].built_filepath).unwrap()
+        };
+        assert_eq!("User-agent: *\nDisallow: /\n", robots(Environment::Staging));
+        assert_eq!("User-agent: *\nAllow: /\n", robots(Environment::Production));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn caller_references() {
+        assert_eq!("xtask-abc", caller_reference(Some("abc"), true, 42));
+        assert_eq!("xtask-abc-42", caller_reference(Some("abc"), false, 42));
+        assert_eq!("xtask-42", caller_reference(None, true, 42));
+    }
+
+    #[test]
+    fn directory_paths() {
+        assert_eq!(
+            vec![
+                "/index.html",
+                "/",
+                "/blog/index.html",
+                "/blog/",
+                "/blog/myindex.html"
+            ],
+            with_directory_paths(
+                ["/index.html", "/blog/index.html", "/blog/myindex.html"]
+                    .map(str::to_owned)
+                    .to_vec()
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn build_hooks() {
+        let manifest = SiteManifest {
+            environment: Environment::Staging,
+            ..Default::default()
+        };
+        manifest
+            .run_hook("pre-build", "test \"$PUSHA_ENVIRONMENT\" = staging")
+            .await
+            .unwrap();
+        assert!(manifest.run_hook("post-build", "exit 3").await.is_err());
+    }
+
+    #[test]
+    fn interpolation() {
+        std::env::set_var("PUSHA_TEST_INTERPOLATE", "from env");
+        let variables = BTreeMap::from([("GIT_SHA", "abc123".to_owned())]);
+        assert_eq!(
+            "<footer>abc123 from env ${PUSHA_TEST_UNSET}</footer>",
+            interpolate(
+                "<footer>${GIT_SHA} ${PUSHA_TEST_INTERPOLATE} ${PUSHA_TEST_UNSET}</footer>",
+                &variables
+            )
+        );
+        let builtins = builtin_variables(&Environment::Staging);
+        assert_eq!("staging", builtins["PUSHA_ENVIRONMENT"]);
+    }
+
+    #[test]
+    fn deploy_summary() {
+        let summary = DeploySummary {
+            new: 3,
+            changed: 9,
+            skipped: 298,
+            deleted: 2,
+            invalidated: 14,
+        };
+        assert_eq!(
+            "uploaded 12 (3 new, 9 changed), skipped 298, deleted 2, invalidated 14 paths",
+            summary.to_string()
+        );
+    }
+
+    #[test]
+    fn page_urls() {
+        let url = |dest: &str| page_url("https://example.com/", std::path::Path::new(dest));
+        assert_eq!("https://example.com/", url("index.html"));
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .build();
+        let file = ManifestFile {
+            origin: "content/blog/index.md".to_owned(),
+            origin_modified: Default::default(),
+            built_filepath: "site/blog/index.html".into(),
+            destination: "blog/index.html".into(),
+            content_type: None,
+            kind: None,
+            content_hash: String::new(),
+            source_hash: None,
+        };
+        assert_eq!(
+            "https://example.com/blog/",
+            file.url(&cfg, &Environment::Production)
+        );
+        assert_eq!("https://example.com/blog/", url("blog/index.html"));
+        assert_eq!("https://example.com/blog/post.html", url("blog/post.html"));
+    }
+
+    #[test]
+    fn render_options_front_matter() {
+        let cli = RenderOptions {
+            heading_anchors: true,
+            toc: false,
+        };
+        let content = "---\ntitle: Hello\ntoc: true\n---\n# Hello\n";
+        assert_eq!(
+            RenderOptions {
+                heading_anchors: true,
+                toc: true
+            },
+            cli.with_front_matter(content)
+        );
+        assert_eq!(cli, cli.with_front_matter("# No front matter\n"));
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode() {
+        let dir = std::env::temp_dir().join(format!("pusha-maintenance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("maintenance.html");
+        std::fs::write(&page, "<p>back soon</p>").unwrap();
+        let cfg = SiteConfig::builder().build();
+        let manifest = SiteManifest {
+            environment: Environment::Custom(format!("maintenance-{}", std::process::id())),
+            ..Default::default()
+        };
+        let store = MemoryStore::default();
+        let invalidator = MemoryInvalidator::default();
+        let website = Website {
+            index_document: Some("index.html".to_owned()),
+            routing_rules: vec![crate::RoutingRule {
+                key_prefix_equals: Some("docs/".to_owned()),
+                replace_key_prefix_with: Some("documents/".to_owned()),
+                ..Default::default()
+            }],
+            ..Website::default()
+        };
+        store.configure_website(Some(&website)).await.unwrap();
+
+        let maintenance =
+            |mode| manifest.maintenance(&cfg, &store, &invalidator, mode, &page, None);
+        maintenance(Maintenance::On).await.unwrap();
+        assert!(store.objects().contains_key("maintenance.html"));
+        assert_eq!(
+            Some("maintenance.html"),
+            store
+                .website()
+                .await
+                .unwrap()
+                .unwrap()
+                .error_document
+                .as_deref()
+        );
+        assert!(store
+            .website()
+            .await
+            .unwrap()
+            .unwrap()
+            .routing_rules
+            .is_empty());
+        assert!(matches!(
+            maintenance(Maintenance::On).await,
+            Err(crate::Error::InMaintenance { .. })
+        ));
+
+        maintenance(Maintenance::Off).await.unwrap();
+        assert_eq!(Some(website), store.website().await.unwrap());
+        assert!(!manifest.website_stash_path().exists());
+        assert!(matches!(
+            maintenance(Maintenance::Off).await,
+            Err(crate::Error::NotInMaintenance { .. })
+        ));
+
+        let redirect = Website {
+            redirect_all_requests_to: Some(crate::RedirectAll {
+                host_name: "example.com".to_owned(),
+                protocol: Some("https".to_owned()),
+            }),
+            ..Website::default()
+        };
+        store.configure_website(Some(&redirect)).await.unwrap();
+        maintenance(Maintenance::On).await.unwrap();
+        maintenance(Maintenance::Off).await.unwrap();
+        assert_eq!(Some(redirect), store.website().await.unwrap());
+        assert_eq!(vec![vec!["/*".to_owned()]; 4], invalidator.invalidations());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn promote_to_memory() {
+        let dir = std::env::temp_dir().join(format!("pusha-promote-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "index.html",
+                "<a href=\"https://staging.example.com/blog/\">blog</a>",
+            ),
+            ("logo.png", "https://staging.example.com"),
+        ];
+        for (path, contents) in files {
+            std::fs::write(dir.join(path), contents).unwrap();
+        }
+        let cfg = SiteConfig::builder()
+            .root_url(|environment| {
+                if environment == &Environment::Staging {
+                    "https://staging.example.com".to_owned()
+                } else {
+                    "https://example.com".to_owned()
+                }
+            })
+            .build();
+        let staging = SiteManifest {
+            environment: Environment::Staging,
+            build_directory: dir.clone(),
+            files: files
+                .into_iter()
+                .map(|(path, contents)| {
+                    let mfile = ManifestFile {
+                        origin: path.to_owned(),
+                        origin_modified: chrono::Utc::now().fixed_offset(),
+                        built_filepath: dir.join(path),
+                        destination: path.into(),
+                        content_type: None,
+                        kind: None,
+                        content_hash: content_hash(contents.as_bytes()),
+                        source_hash: None,
+                    };
+                    (path.to_owned(), mfile)
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let target = Environment::Custom(format!("promote-{}", std::process::id()));
+        let store = MemoryStore::default();
+        let invalidator = MemoryInvalidator::default();
+        assert!(matches!(
+            staging
+                .promote(&cfg, &store, &invalidator, Environment::Staging, true, None)
+                .await,
+            Err(crate::Error::PromoteToSelf { .. })
+        ));
+        let promoted = staging
+            .promote(&cfg, &store, &invalidator, target.clone(), true, None)
+            .await
+            .unwrap();
+        std::fs::remove_file(format!("{target}.yaml")).unwrap();
+
+        let objects = store.objects();
+        assert_eq!(
+            b"<a href=\"https://example.com/blog/\">blog</a>".as_slice(),
+            objects["index.html"].body
+        );
+        assert_eq!(
+            b"https://staging.example.com".as_slice(),
+            objects["logo.png"].body
+        );
+        assert_eq!(&target, promoted.environment());
+        assert_eq!(
+            content_hash(&objects["index.html"].body),
+            promoted.files()["index.html"].content_hash()
+        );
+        assert_eq!(
+            dir.join("logo.png"),
+            promoted.files()["logo.png"].built_filepath()
+        );
+        assert_eq!(1, invalidator.invalidations().len());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(promoted.build_directory()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aggregated_upload_errors() {
+        let cfg = SiteConfig::builder().build();
+        let manifest = SiteManifest::default();
+        let files = ["missing.html", "also/missing.css"]
+            .into_iter()
+            .map(|path| ManifestFile {
+                origin: path.to_owned(),
+                origin_modified: chrono::Utc::now().fixed_offset(),
+                built_filepath: std::env::temp_dir().join("pusha-missing").join(path),
+                destination: path.into(),
+                content_type: None,
+                kind: None,
+                content_hash: String::new(),
+                source_hash: None,
+            })
+            .collect::<Vec<_>>();
+        let store = MemoryStore::default();
+        let result = manifest
+            .upload_files(&cfg, &store, &files.iter().collect::<Vec<_>>(), None)
+            .await;
+        let Err(crate::Error::Uploads { failures }) = result else {
+            panic!("expected every upload to fail, got {result:?}");
+        };
+        assert_eq!(
+            vec!["missing.html", "also/missing.css"],
+            failures
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sorted_files() {
+        let dir = std::env::temp_dir().join(format!("pusha-sorted-{}", std::process::id()));
+        for path in ["b.md", "a/z.md", "c/b.md", "a/b/c.md", "A.md"] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let files = get_files(&dir).unwrap();
+        assert_eq!(
+            vec!["A.md", "a/b/c.md", "a/z.md", "b.md", "c/b.md"],
+            files
+                .iter()
+                .map(|path| to_s3_key(path.strip_prefix(&dir).unwrap()))
+                .collect::<Vec<_>>()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compressed_uploads() {
+        let dir = std::env::temp_dir().join(format!("pusha-compress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = ["index.html", "logo.png"]
+            .into_iter()
+            .map(|path| {
+                std::fs::write(dir.join(path), "<p>hello</p>".repeat(10)).unwrap();
+                ManifestFile {
+                    origin: path.to_owned(),
+                    origin_modified: chrono::Utc::now().fixed_offset(),
+                    built_filepath: dir.join(path),
+                    destination: path.into(),
+                    content_type: None,
+                    kind: None,
+                    content_hash: String::new(),
+                    source_hash: None,
+                }
+            })
+            .collect::<Vec<_>>();
+        let cfg = SiteConfig::builder().build();
+        let store = MemoryStore::default();
+        SiteManifest::default()
+            .upload_files(
+                &cfg,
+                &store,
+                &files.iter().collect::<Vec<_>>(),
+                Some(Compression::Both),
+            )
+            .await
+            .unwrap();
+        let objects = store.objects();
+        let encoding = |key: &str| objects[key].content_encoding.as_deref();
+        assert_eq!(Some("gzip"), encoding("index.html"));
+        assert_eq!(Some("br"), encoding("index.html.br"));
+        assert_eq!(
+            objects["index.html"].content_type,
+            objects["index.html.br"].content_type
+        );
+        assert_eq!(None, encoding("logo.png"));
+        assert_eq!(3, objects.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_formats() {
+        let environment = Environment::Custom(format!("formats-{}", std::process::id()));
+        let mut manifest = SiteManifest {
+            environment: environment.clone(),
+            build_directory: "site".into(),
+            files: [(
+                "content/index.md".to_owned(),
+                ManifestFile {
+                    origin: "content/index.md".to_owned(),
+                    origin_modified: chrono::Utc::now().fixed_offset(),
+                    built_filepath: "site/index.html".into(),
+                    destination: "index.html".into(),
+                    content_type: Some("text/html".to_owned()),
+                    kind: Some(FileKind::Pages),
+                    content_hash: content_hash(b"<p>home</p>"),
+                    source_hash: None,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        for format in ManifestFormat::ALL {
+            manifest.format = format;
+            manifest.save();
+            let path = format!("{environment}.{}", format.extension());
+            assert_eq!(format, ManifestFormat::of(&path));
+            // Saving in another format replaces the manifest in the previous one.
+            let saved = ManifestFormat::ALL
+                .into_iter()
+                .filter(|format| {
+                    std::path::Path::new(&format!("{environment}.{}", format.extension())).exists()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(vec![format], saved);
+            let read = SiteManifest::new(environment.clone(), "elsewhere".into()).unwrap();
+            assert_eq!(format, read.format);
+            assert_eq!(manifest.files().len(), read.files().len());
+            assert_eq!(
+                manifest.files()["content/index.md"].content_hash(),
+                read.files()["content/index.md"].content_hash()
+            );
+        }
+        let path = format!("{environment}.{}", manifest.format.extension());
+        std::fs::write(&path, "files: [").unwrap();
+        assert!(matches!(
+            SiteManifest::new(environment.clone(), "site".into()),
+            Err(crate::Error::ParseManifest { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn manifests_are_not_content() {
+        let dir =
+            std::env::temp_dir().join(format!("pusha-manifest-content-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("blog")).unwrap();
+        for path in [
+            "index.md",
+            "staging.yaml",
+            "staging.website.yaml",
+            "blog/staging.json",
+            "production.toml",
+        ] {
+            std::fs::write(dir.join(path), "").unwrap();
+        }
+        let files = get_content_files(&dir, &Environment::Staging).unwrap();
+        assert_eq!(
+            vec!["index.md", "production.toml"],
+            files
+                .iter()
+                .map(|path| to_s3_key(path.strip_prefix(&dir).unwrap()))
+                .collect::<Vec<_>>()
+        );
+        assert!(is_manifest_file("staging.toml", &Environment::Staging));
+        assert!(!is_manifest_file("staging.yaml.bak", &Environment::Staging));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_going() {
+        let error = |origin| render_error(origin, std::fmt::Error);
+        let mut options = BuildOptions::default();
+        let mut failures = BuildFailures::default();
+        let Err(crate::Error::RenderPage { origin, .. }) =
+            failures.fail(&options, error("content/a.md"))
+        else {
+            panic!("expected the build to stop");
+        };
+        assert_eq!("content/a.md", origin);
+        failures.finish().unwrap();
+
+        options.keep_going = true;
+        let mut failures = BuildFailures::default();
+        failures.fail(&options, error("content/a.md")).unwrap();
+        failures.fail(&options, error("content/b.md")).unwrap();
+        let Err(e @ crate::Error::Builds { .. }) = failures.finish() else {
+            panic!("expected the failures to be reported");
+        };
+        let message = e.to_string();
+        assert!(message.starts_with("2 pages failed to build"));
+        assert!(message.contains("could not render 'content/b.md'"));
+    }
+
+    #[tokio::test]
+    async fn unchanged_external_pages() {
+        static RENDERS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        struct Counting;
+        impl Renderer for Counting {
+            type Error = std::convert::Infallible;
+
+            fn render_content(
+                _: &SiteConfig,
+                _: &Environment,
+                content: String,
+                _: &str,
+            ) -> Result<String, Self::Error> {
+                RENDERS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(content)
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("pusha-unchanged-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("devlog.md");
+        std::fs::write(&source, "# Devlog\n").unwrap();
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .build();
+        let pages = || {
+            [ExternalPage {
+                source_url: PageSource::Local(source.clone()),
+                local_path: "devlog/index.html".into(),
+                headers: Default::default(),
+            }]
+        };
+        let mut manifest = SiteManifest {
+            environment: Environment::Staging,
+            build_directory: dir.join("site"),
+            ..Default::default()
+        };
+        let options = BuildOptions {
+            search_index: true,
+            ..BuildOptions::default()
+        };
+        for _ in 0..2 {
+            manifest
+                .build::<Counting>(&cfg, &options, pages())
+                .await
+                .unwrap();
+        }
+        assert_eq!(1, RENDERS.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(
+            std::fs::read_to_string(dir.join("site").join(crate::search::SEARCH_INDEX))
+                .unwrap()
+                .contains(r#""url":"https://example.com/devlog/""#)
+        );
+        assert!(manifest.files[source.to_str().unwrap()]
+            .source_hash
+            .is_some());
+
+        std::fs::write(&source, "# Devlog\n\nNew entry\n").unwrap();
+        manifest
+            .build::<Counting>(&cfg, &options, pages())
+            .await
+            .unwrap();
+        assert_eq!(2, RENDERS.load(std::sync::atomic::Ordering::SeqCst));
+        // Options that don't change the rendered page keep the previous build.
+        let unrelated = BuildOptions {
+            slowest: 3,
+            keep_going: true,
+            fetch_concurrency: 1,
+            ..options.clone()
+        };
+        manifest
+            .build::<Counting>(&cfg, &unrelated, pages())
+            .await
+            .unwrap();
+        assert_eq!(2, RENDERS.load(std::sync::atomic::Ordering::SeqCst));
+        let options = BuildOptions {
+            toc: true,
+            ..options
+        };
+        manifest
+            .build::<Counting>(&cfg, &options, pages())
+            .await
+            .unwrap();
+        assert_eq!(3, RENDERS.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(std::fs::read_to_string(dir.join("site/devlog/index.html"))
+            .unwrap()
+            .contains("New entry"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchanged_external_page_outputs() {
+        static RENDERS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        struct WithOutputs;
+        impl Renderer for WithOutputs {
+            type Error = std::convert::Infallible;
+
+            fn render_content(
+                _: &SiteConfig,
+                _: &Environment,
+                content: String,
+                _: &str,
+            ) -> Result<String, Self::Error> {
+                RENDERS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(content)
+            }
+
+            fn render_outputs(
+                _: &SiteConfig,
+                page: &PageContext,
+                content: &str,
+            ) -> Result<Vec<(std::path::PathBuf, Vec<u8>)>, Self::Error> {
+                Ok(vec![(
+                    page.destination.with_extension("txt"),
+                    content.as_bytes().to_vec(),
+                )])
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("pusha-outputs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("devlog.md");
+        std::fs::write(&source, "# Devlog\n").unwrap();
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .build();
+        let pages = || {
+            [ExternalPage {
+                source_url: PageSource::Local(source.clone()),
+                local_path: "devlog/index.html".into(),
+                headers: Default::default(),
+            }]
+        };
+        let mut manifest = SiteManifest {
+            environment: Environment::Staging,
+            build_directory: dir.join("site"),
+            ..Default::default()
+        };
+        let key = format!("{}#devlog/index.txt", source.display());
+        for _ in 0..2 {
+            manifest
+                .build::<WithOutputs>(&cfg, &BuildOptions::default(), pages())
+                .await
+                .unwrap();
+            assert!(manifest.files.contains_key(&key));
+            assert_eq!(
+                "# Devlog\n",
+                std::fs::read_to_string(dir.join("site/devlog/index.txt")).unwrap()
+            );
+        }
+        assert_eq!(1, RENDERS.load(std::sync::atomic::Ordering::SeqCst));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deploy_info_without_a_build() {
+        let mut manifest = SiteManifest {
+            environment: Environment::Staging,
+            build_directory: std::env::temp_dir()
+                .join(format!("pusha-no-build-{}", std::process::id())),
+            ..Default::default()
+        };
+        assert!(matches!(
+            manifest.write_deploy_info(std::path::Path::new(""), None),
+            Err(crate::Error::WriteFile { .. })
+        ));
+        assert!(manifest.files.is_empty());
+    }
+
+    #[test]
+    fn webp_asset_variants() {
+        let dir = std::env::temp_dir().join(format!("pusha-webp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let img =
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 0]));
+        let photo = dir.join("photo.png");
+        img.save(&photo).unwrap();
+        let mut manifest = SiteManifest {
+            environment: Environment::Staging,
+            build_directory: dir.join("site"),
+            ..Default::default()
+        };
+        let options = BuildOptions {
+            optimize_images: true,
+            ..BuildOptions::default()
+        };
+        manifest
+            .build_asset(&options, &photo, "img/photo.png".into())
+            .unwrap();
+        let variant = &manifest.files[&format!("{}#webp", photo.display())];
+        assert_eq!(
+            std::path::Path::new("img/photo.png.webp"),
+            variant.destination
+        );
+        assert_eq!(Some("image/webp"), variant.content_type.as_deref());
+        assert!(dir.join("site/img/photo.png.webp").is_file());
+        assert!(dir.join("site/img/photo.png").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolved_config() {
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com".to_owned())
+            .s3_bucket(|_| Some("example.com".to_owned()))
+            .cloudfront_distro(|_| vec!["E1".to_owned(), "E2".to_owned()])
+            .region(|_| Some("us-east-1".to_owned()))
+            .build();
+        let aws =
+            AwsOptions::default().with_overrides(Some("preview.example.com".to_owned()), vec![]);
+        let resolved = ResolvedConfig::resolve(&cfg, &Environment::Staging, &aws).await;
+        assert_eq!("https://example.com", resolved.root_url);
+        assert_eq!(Some("preview.example.com"), resolved.s3_bucket.as_deref());
+        assert_eq!(vec!["E1", "E2"], resolved.cloudfront_distro);
+        assert_eq!("us-east-1", resolved.region);
+        assert_eq!(
+            BTreeMap::from([("bucket", "preview.example.com".to_owned())]),
+            resolved.overrides
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_copies_are_not_orphans() {
+        let dir = std::env::temp_dir().join(format!("pusha-br-orphans-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>hello</p>").unwrap();
+        let manifest = SiteManifest {
+            files: [(
+                "index.html".to_owned(),
+                ManifestFile {
+                    origin: "index.html".to_owned(),
+                    origin_modified: chrono::Utc::now().fixed_offset(),
+                    built_filepath: dir.join("index.html"),
+                    destination: "index.html".into(),
+                    content_type: None,
+                    kind: None,
+                    content_hash: String::new(),
+                    source_hash: None,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let cfg = SiteConfig::builder().build();
+        let store = MemoryStore::default();
+        let options = DeployOptions {
+            compress: Some(Compression::Both),
+            delete_orphans: true,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let stored = stored_keys(&store, std::path::Path::new("")).await.unwrap();
+            manifest
+                .upload_files(
+                    &cfg,
+                    &store,
+                    &manifest.files.values().collect::<Vec<_>>(),
+                    options.compress,
+                )
+                .await
+                .unwrap();
+            let deleted = manifest
+                .delete_orphans(&cfg, &store, stored, &options)
+                .await
+                .unwrap();
+            assert!(deleted.is_empty());
+        }
+        assert!(store.objects().contains_key("index.html.br"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rollback_checks_built_files() {
+        let dir = std::env::temp_dir().join(format!("pusha-rollback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let environment = Environment::Custom(format!("rollback-{}", std::process::id()));
+        let manifest = |paths: &[(&str, &str)]| SiteManifest {
+            environment: environment.clone(),
+            files: paths
+                .iter()
+                .map(|(path, contents)| {
+                    let mfile = ManifestFile {
+                        origin: path.to_string(),
+                        origin_modified: chrono::Utc::now().fixed_offset(),
+                        built_filepath: dir.join(path),
+                        destination: path.into(),
+                        content_type: None,
+                        kind: None,
+                        content_hash: content_hash(contents.as_bytes()),
+                        source_hash: None,
+                    };
+                    (path.to_string(), mfile)
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut current = manifest(&[("index.html", "new"), ("added.html", "added")]);
+        let previous = || manifest(&[("index.html", "old")]);
+        let cfg = SiteConfig::builder().build();
+        let store = MemoryStore::default();
+        let invalidator = MemoryInvalidator::default();
+        std::fs::write(dir.join("index.html"), "new").unwrap();
+
+        let other = SiteManifest {
+            environment: Environment::Staging,
+            ..previous()
+        };
+        let result = current
+            .rollback(&cfg, &store, &invalidator, other, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::RollbackEnvironment { .. })
+        ));
+        let result = current
+            .rollback(&cfg, &store, &invalidator, previous(), None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::RollbackBuild { count: 1 })
+        ));
+        assert!(store.objects().is_empty());
+
+        std::fs::write(dir.join("index.html"), "old").unwrap();
+        current
+            .rollback(&cfg, &store, &invalidator, previous(), None)
+            .await
+            .unwrap();
+        assert_eq!(b"old".to_vec(), store.objects()["index.html"].body);
+        assert_eq!(1, current.files.len());
+        std::fs::remove_file(format!("{environment}.yaml")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreadable_local_pages() {
+        let fetcher = crate::fetch::Fetcher::new(1, None);
+        let source = PageSource::Local("does/not/exist.md".into());
+        let result = source.read(&fetcher, &BTreeMap::new(), None).await;
+        assert!(matches!(result, Err(crate::Error::ReadPage { .. })));
     }
 }
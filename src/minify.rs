@@ -0,0 +1,81 @@
+//! Minification of HTML, CSS and JS build outputs.
+
+/// Files with an average line length above this are assumed to be minified already.
+const MINIFIED_LINE_LENGTH: usize = 500;
+
+/// Returns whether the content looks like it has already been minified.
+fn looks_minified(path: &std::path::Path, content: &str) -> bool {
+    let is_min_file = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().ends_with(".min"))
+        .unwrap_or_default();
+    let lines = content.lines().count().max(1);
+    is_min_file || content.len() / lines > MINIFIED_LINE_LENGTH
+}
+
+/// Minify the bytes destined for `path`, based on its extension.
+///
+/// Returns the bytes untouched if the file isn't HTML, CSS or JS, is larger than
+/// `max_size`, isn't UTF-8, or looks like it has already been minified.
+pub fn minify(path: &std::path::Path, bytes: Vec<u8>, max_size: u64) -> Vec<u8> {
+    let ext = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext @ ("html" | "htm" | "css" | "js")) => ext,
+        _ => return bytes,
+    };
+    if bytes.len() as u64 > max_size {
+        log::debug!(
+            "not minifying '{}', it is larger than {max_size} bytes",
+            path.display()
+        );
+        return bytes;
+    }
+    let content = match std::str::from_utf8(&bytes) {
+        Ok(content) => content,
+        Err(_) => {
+            log::warn!("not minifying '{}', it is not utf8", path.display());
+            return bytes;
+        }
+    };
+    if looks_minified(path, content) {
+        log::debug!("'{}' looks minified already", path.display());
+        return bytes;
+    }
+
+    let minified = match ext {
+        "css" => minifier::css::minify(content).map(|m| m.to_string()),
+        "js" => minifier::js::minify(content).map(|m| m.to_string()),
+        _ => {
+            let mut cfg = minify_html::Cfg::new();
+            cfg.minify_css = true;
+            cfg.minify_js = true;
+            return minify_html::minify(&bytes, &cfg);
+        }
+    };
+    match minified {
+        Ok(minified) => minified.into_bytes(),
+        Err(e) => {
+            log::warn!("could not minify '{}': {e}", path.display());
+            bytes
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn minify_sanity() {
+        let css = b"body {\n    color: red;\n}\n".to_vec();
+        let path = std::path::Path::new("style.css");
+        assert_eq!(
+            b"body{color:red;}".to_vec(),
+            minify(path, css.clone(), 1024)
+        );
+        assert_eq!(
+            css,
+            minify(std::path::Path::new("style.min.css"), css.clone(), 1024)
+        );
+        assert_eq!(css, minify(path, css.clone(), 4));
+    }
+}
@@ -0,0 +1,113 @@
+//! The critical assets referenced by each rendered page, written with
+//! `--preload-map` and listed in the `--timings` report.
+//!
+//! The map is keyed by the path each page is served at, so its `Link` header
+//! values can be fed into a CloudFront response headers policy per path.
+
+/// A local stylesheet or script referenced by a page.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Preload {
+    /// The path of the asset, relative to the root url, like "/style.css".
+    pub href: String,
+    /// The kind of asset, as in `<link rel="preload" as="...">`.
+    #[serde(rename = "as")]
+    pub kind: &'static str,
+}
+
+/// The critical assets of one page.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct PagePreloads {
+    /// The assets in the order they are referenced.
+    pub assets: Vec<Preload>,
+    /// A `Link` header value preloading all of the assets.
+    pub link: String,
+}
+
+impl PagePreloads {
+    /// Returns the local stylesheets and scripts referenced by `html`. Absolute
+    /// links are only local if they start with `root_url`.
+    pub fn new(html: &str, root_url: &str) -> Self {
+        let tag = regex::Regex::new(r"(?is)<(link|script)\b[^>]*>").unwrap();
+        let mut assets = Vec::<Preload>::new();
+        for tag in tag.captures_iter(html) {
+            let (kind, href) = if tag[1].eq_ignore_ascii_case("script") {
+                ("script", attribute(&tag[0], "src"))
+            } else if attribute(&tag[0], "rel")
+                .is_some_and(|rel| rel.split_whitespace().any(|rel| rel == "stylesheet"))
+            {
+                ("style", attribute(&tag[0], "href"))
+            } else {
+                continue;
+            };
+            let Some(href) = href.and_then(|href| local_path(href, root_url)) else {
+                continue;
+            };
+            if !assets.iter().any(|asset| asset.href == href) {
+                assets.push(Preload { href, kind });
+            }
+        }
+        let link = assets
+            .iter()
+            .map(|asset| format!("<{}>; rel=preload; as={}", asset.href, asset.kind))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self { assets, link }
+    }
+}
+
+/// Returns the value of the attribute `name` of an HTML `tag`.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let attribute = regex::Regex::new(&format!(
+        r#"(?i)\s{name}\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#
+    ))
+    .unwrap();
+    let captures = attribute.captures(tag)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .or_else(|| captures.get(3))
+        .map(|value| value.as_str())
+}
+
+/// Returns the root-relative path of `href` if it is served by the site.
+fn local_path(href: &str, root_url: &str) -> Option<String> {
+    let root_url = root_url.trim_end_matches('/');
+    let path = match href.strip_prefix(root_url) {
+        Some(path) if !root_url.is_empty() && path.starts_with('/') => path,
+        _ if href.starts_with('/') && !href.starts_with("//") => href,
+        _ => return None,
+    };
+    Some(path.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn page_preloads() {
+        let html = r#"<head>
+<link rel="stylesheet" href="/style.css">
+<link rel='icon' href='/favicon.ico'>
+<link href=https://example.com/print.css rel="alternate stylesheet">
+<link rel="stylesheet" href="https://cdn.example.org/font.css">
+<script src="/app.js" defer></script>
+<script>let inline = true;</script>
+<script src="/app.js"></script>
+</head>"#;
+        let preloads = PagePreloads::new(html, "https://example.com/");
+        assert_eq!(
+            vec!["/style.css", "/print.css", "/app.js"],
+            preloads
+                .assets
+                .iter()
+                .map(|asset| asset.href.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "</style.css>; rel=preload; as=style, </print.css>; rel=preload; as=style, \
+             </app.js>; rel=preload; as=script",
+            preloads.link
+        );
+    }
+}
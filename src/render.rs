@@ -0,0 +1,269 @@
+//! A built-in markdown [`Renderer`], enabled with the `default-renderer` feature.
+use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+
+use crate::{Environment, PageContext, RenderOptions, Renderer, SiteConfig};
+
+/// Renders markdown to HTML with `pulldown-cmark`, wrapped in a minimal page.
+///
+/// The page's extra classes are added to the content `div`, and root-relative
+/// links like `/blog/` are prefixed with the environment's root url and the page's
+/// base path. Heading anchors and the table of contents are supported.
+pub struct DefaultRenderer;
+
+/// A heading found in the rendered markdown.
+struct Heading {
+    level: pulldown_cmark::HeadingLevel,
+    id: String,
+    text: String,
+}
+
+/// Returns an `id` for a heading with the given text, like "getting-started".
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+/// Prefixes root-relative link destinations with `root_url`.
+fn with_root_url<'a>(root_url: &str, destination: CowStr<'a>) -> CowStr<'a> {
+    if destination.starts_with('/') && !destination.starts_with("//") {
+        format!("{}{destination}", root_url.trim_end_matches('/')).into()
+    } else {
+        destination
+    }
+}
+
+/// Escapes text for use in HTML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    pulldown_cmark::html::push_html(
+        &mut escaped,
+        std::iter::once(Event::Text(text.to_owned().into())),
+    );
+    escaped
+}
+
+impl Renderer for DefaultRenderer {
+    type Error = std::convert::Infallible;
+
+    fn render_content(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        content: String,
+        extra_classes: &str,
+    ) -> Result<String, Self::Error> {
+        Self::render_content_with_options(
+            cfg,
+            environment,
+            content,
+            extra_classes,
+            &RenderOptions::default(),
+        )
+    }
+
+    fn render_content_with_options(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        content: String,
+        extra_classes: &str,
+        options: &RenderOptions,
+    ) -> Result<String, Self::Error> {
+        Ok(render(
+            &(cfg.root_url)(environment),
+            content,
+            extra_classes,
+            options,
+        ))
+    }
+
+    fn render_page(
+        cfg: &SiteConfig,
+        page: &PageContext,
+        content: String,
+    ) -> Result<String, Self::Error> {
+        let root_url = format!(
+            "{}{}",
+            (cfg.root_url)(page.environment).trim_end_matches('/'),
+            page.base_path
+        );
+        Ok(render(
+            &root_url,
+            content,
+            page.extra_classes,
+            &page.options,
+        ))
+    }
+}
+
+/// Render markdown `content` to an HTML page, with root-relative links prefixed
+/// with `root_url`.
+fn render(root_url: &str, content: String, extra_classes: &str, options: &RenderOptions) -> String {
+    let parser_options = pulldown_cmark::Options::ENABLE_TABLES
+        | pulldown_cmark::Options::ENABLE_STRIKETHROUGH
+        | pulldown_cmark::Options::ENABLE_HEADING_ATTRIBUTES
+        | pulldown_cmark::Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+    let mut events = pulldown_cmark::Parser::new_ext(&content, parser_options)
+        .map(|event| match event {
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: with_root_url(root_url, dest_url),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Image {
+                link_type,
+                dest_url: with_root_url(root_url, dest_url),
+                title,
+                id,
+            }),
+            event => event,
+        })
+        .collect::<Vec<_>>();
+
+    let mut headings = vec![];
+    for (i, event) in events.iter().enumerate() {
+        if let Event::Start(Tag::Heading { level, id, .. }) = event {
+            let text = events[i + 1..]
+                .iter()
+                .take_while(|event| !matches!(event, Event::End(TagEnd::Heading(_))))
+                .filter_map(|event| match event {
+                    Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect::<String>();
+            let id = match id {
+                Some(id) => id.to_string(),
+                None => {
+                    let slug = slugify(&text);
+                    let taken = |id: &str| headings.iter().any(|h: &Heading| h.id == id);
+                    let mut id = slug.clone();
+                    let mut n = 1;
+                    while taken(&id) {
+                        id = format!("{slug}-{n}");
+                        n += 1;
+                    }
+                    id
+                }
+            };
+            headings.push(Heading {
+                level: *level,
+                id,
+                text,
+            });
+        }
+    }
+
+    // The table of contents links to the headings, so it needs their anchors too.
+    if options.heading_anchors || options.toc {
+        let mut ids = headings.iter().map(|h| h.id.clone());
+        for event in events.iter_mut() {
+            if let Event::Start(Tag::Heading { id, .. }) = event {
+                *id = ids.next().map(CowStr::from);
+            }
+        }
+    }
+
+    let mut body = String::new();
+    if options.toc && !headings.is_empty() {
+        body.push_str("<nav class=\"toc\">\n<ul>\n");
+        for heading in headings.iter() {
+            body.push_str(&format!(
+                "<li class=\"toc-{}\"><a href=\"#{}\">{}</a></li>\n",
+                heading.level,
+                heading.id,
+                escape(&heading.text)
+            ));
+        }
+        body.push_str("</ul>\n</nav>\n");
+    }
+    pulldown_cmark::html::push_html(&mut body, events.into_iter());
+
+    let title = headings
+        .iter()
+        .find(|h| h.level == pulldown_cmark::HeadingLevel::H1)
+        .map(|h| escape(&h.text))
+        .unwrap_or_default();
+    let classes = format!("content {extra_classes}");
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{title}</title>
+</head>
+<body>
+<div class=\"{}\">
+{body}</div>
+</body>
+</html>
+",
+        classes.trim()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_renderer() {
+        let cfg = SiteConfig::builder()
+            .root_url(|_| "https://example.com/".to_owned())
+            .build();
+        let content = "---\ntoc: true\n---\n# Hello World\n\nSee [the blog](/blog/) \
+                       or [elsewhere](https://example.org).\n\n## Hello World\n"
+            .to_owned();
+        let options = RenderOptions {
+            heading_anchors: true,
+            toc: true,
+        };
+        let html = DefaultRenderer::render_content_with_options(
+            &cfg,
+            &Environment::Local,
+            content,
+            "post",
+            &options,
+        )
+        .unwrap();
+        assert!(html.contains("<title>Hello World</title>"));
+        assert!(html.contains("<div class=\"content post\">"));
+        assert!(html.contains("<h1 id=\"hello-world\">Hello World</h1>"));
+        assert!(html.contains("<h2 id=\"hello-world-1\">Hello World</h2>"));
+        assert!(html.contains("<a href=\"#hello-world-1\">Hello World</a>"));
+        assert!(html.contains("href=\"https://example.com/blog/\""));
+        assert!(html.contains("href=\"https://example.org\""));
+        assert!(!html.contains("toc: true"));
+
+        let toc_only = RenderOptions {
+            heading_anchors: false,
+            toc: true,
+        };
+        let html = DefaultRenderer::render_content_with_options(
+            &cfg,
+            &Environment::Local,
+            "# Hello World\n".to_owned(),
+            "post",
+            &toc_only,
+        )
+        .unwrap();
+        assert!(html.contains("<a href=\"#hello-world\">Hello World</a>"));
+        assert!(html.contains("<h1 id=\"hello-world\">Hello World</h1>"));
+    }
+}
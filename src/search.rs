@@ -0,0 +1,127 @@
+//! A client-side search index of the rendered pages, written with `--search-index`.
+//!
+//! The index is a JSON list of documents with `id`, `url`, `title` and `text`
+//! fields, which can be loaded directly by `minisearch` or `lunr`.
+
+/// The file the index is written to, relative to the root of the site.
+pub const SEARCH_INDEX: &str = "search-index.json";
+
+/// One page in the search index.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct SearchEntry {
+    /// The path of the page, relative to the root of the site.
+    pub id: String,
+    /// The live URL of the page.
+    pub url: String,
+    /// The front matter `title` of the page, or its first `# ` heading.
+    pub title: String,
+    /// The text of the rendered page, with markup removed.
+    pub text: String,
+}
+
+/// Returns the search entry of a page with the given unrendered `content` and
+/// rendered `html`, or `None` if its front matter marks it as a draft.
+pub fn entry(id: String, url: String, content: &str, html: &str) -> Option<SearchEntry> {
+    #[derive(Default, serde::Deserialize)]
+    struct FrontMatter {
+        title: Option<String>,
+        #[serde(default)]
+        draft: bool,
+    }
+
+    let (front_matter, body) = content
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.split_once("\n---"))
+        .map(|(yaml, body)| {
+            (
+                serde_yaml::from_str::<FrontMatter>(yaml).unwrap_or_default(),
+                body,
+            )
+        })
+        .unwrap_or_else(|| (FrontMatter::default(), content));
+    if front_matter.draft {
+        log::debug!("not indexing draft '{id}'");
+        return None;
+    }
+    let title = front_matter.title.unwrap_or_else(|| {
+        body.lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .unwrap_or_default()
+            .trim()
+            .to_owned()
+    });
+    Some(SearchEntry {
+        id,
+        url,
+        title,
+        text: text(html),
+    })
+}
+
+/// Returns the search entry of an already built page, titled by its first
+/// `<h1>` or else its `<title>`.
+pub fn html_entry(id: String, url: String, html: &str) -> SearchEntry {
+    let heading = regex::Regex::new(r"(?is)<h1\b[^>]*>(.*?)</h1>").unwrap();
+    let title = regex::Regex::new(r"(?is)<title\b[^>]*>(.*?)</title>").unwrap();
+    let title = heading
+        .captures(html)
+        .or_else(|| title.captures(html))
+        .map(|captures| text(&captures[1]))
+        .unwrap_or_default();
+    SearchEntry {
+        id,
+        url,
+        title,
+        text: text(html),
+    }
+}
+
+/// Returns the text of `html`, without tags, scripts or styles, and with runs of
+/// whitespace collapsed.
+fn text(html: &str) -> String {
+    let hidden = regex::Regex::new(r"(?is)<(script|style)\b.*?</(script|style)>").unwrap();
+    let tag = regex::Regex::new(r"(?s)<[^>]*>").unwrap();
+    let html = hidden.replace_all(html, " ");
+    let text = tag.replace_all(&html, " ");
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn search_entries() {
+        let html = "<h1>Hello</h1>\n<script>let x = 1;</script><p>Fish &amp;\n  chips</p>";
+        let entry = |content: &str| {
+            entry(
+                "blog/post.html".to_owned(),
+                "https://example.com/blog/post.html".to_owned(),
+                content,
+                html,
+            )
+        };
+        let indexed = entry("# Hello\n\nFish & chips\n").unwrap();
+        assert_eq!("Hello", indexed.title);
+        assert_eq!("Hello Fish & chips", indexed.text);
+        assert_eq!(
+            "Greetings",
+            entry("---\ntitle: Greetings\n---\n# Hello\n")
+                .unwrap()
+                .title
+        );
+        assert_eq!(None, entry("---\ndraft: true\n---\n# Hello\n"));
+
+        let built = format!("<html><head><title>Site</title></head><body>{html}</body></html>");
+        let indexed = html_entry(String::new(), String::new(), &built);
+        assert_eq!("Hello", indexed.title);
+        assert_eq!("Site Hello Fish & chips", indexed.text);
+    }
+}
@@ -0,0 +1,1140 @@
+//! Object storage and cache invalidation backends.
+//!
+//! Deploys go through the [`ObjectStore`] and [`CacheInvalidator`] traits, which are
+//! implemented for s3 and cloudfront, and in memory for testing.
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use futures_util::{StreamExt, TryStreamExt};
+use snafu::{OptionExt, ResultExt};
+
+use crate::{error::*, AwsOptions, Environment, SiteConfig};
+
+/// An object to upload.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PutObject {
+    /// The key of the object.
+    pub key: String,
+    /// Local path of the file to upload.
+    pub path: std::path::PathBuf,
+    /// The content type to serve the object with.
+    pub content_type: String,
+    /// The `Cache-Control` header to serve the object with, if any.
+    pub cache_control: Option<String>,
+    /// The `Content-Encoding` the file is compressed with, if any.
+    pub content_encoding: Option<String>,
+    /// The tags of the object, by key.
+    pub tags: BTreeMap<String, String>,
+    /// The hex encoded sha256 of the file, stored with the object so later
+    /// deploys can tell whether it changed.
+    pub content_hash: Option<String>,
+    /// Only create the object, failing with [`Error::AlreadyExists`] instead of
+    /// replacing an existing one.
+    pub create_only: bool,
+}
+
+/// What is stored about an uploaded object.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ObjectHead {
+    /// The size of the object in bytes.
+    pub content_length: u64,
+    /// The content type the object is served with.
+    pub content_type: Option<String>,
+    /// The `Cache-Control` header the object is served with.
+    pub cache_control: Option<String>,
+    /// The content hash stored with the object, if any.
+    pub content_hash: Option<String>,
+}
+
+/// How a store serves its objects as a static website.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Website {
+    /// The object served for directories, like "index.html", unless every
+    /// request is redirected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_document: Option<String>,
+    /// The object served for missing objects, like "404.html", if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_document: Option<String>,
+    /// The host every request is redirected to, instead of being served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_all_requests_to: Option<RedirectAll>,
+    /// Redirects applied to the requests that match their condition.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routing_rules: Vec<RoutingRule>,
+}
+
+/// Where a [`Website`] redirects every request to.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RedirectAll {
+    /// The host redirected to.
+    pub host_name: String,
+    /// "http" or "https", or `None` for the protocol of the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+}
+
+/// A redirect of a [`Website`], applied when its condition matches.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RoutingRule {
+    /// Only redirect requests that fail with this status code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_error_code_returned_equals: Option<String>,
+    /// Only redirect requests for keys with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_prefix_equals: Option<String>,
+    /// The host redirected to, or `None` for the same host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_name: Option<String>,
+    /// The status code of the redirect, like "301".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_redirect_code: Option<String>,
+    /// "http" or "https", or `None` for the protocol of the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    /// What the matched `key_prefix_equals` is replaced with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace_key_prefix_with: Option<String>,
+    /// The key redirected to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace_key_with: Option<String>,
+}
+
+/// Somewhere the built site is uploaded to.
+pub trait ObjectStore {
+    /// The name of the store, for logging.
+    fn name(&self) -> &str;
+
+    /// Upload an object, replacing any existing object with the same key unless
+    /// [`PutObject::create_only`] is set.
+    fn put_object(&self, object: PutObject) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Returns what is stored about the object with the given key, or `None` if
+    /// there is no such object.
+    fn head_object(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<ObjectHead>, Error>> + Send;
+
+    /// Delete the object with the given key.
+    fn delete_object(&self, key: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// List the keys of every object whose key starts with `prefix`.
+    fn list_objects(&self, prefix: &str)
+        -> impl Future<Output = Result<Vec<String>, Error>> + Send;
+
+    /// Delete the objects with the given keys.
+    fn delete_objects(&self, keys: Vec<String>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Returns how the store serves a static website, or `None` if it doesn't.
+    fn website(&self) -> impl Future<Output = Result<Option<Website>, Error>> + Send;
+
+    /// Configure the store to serve a static website, or to stop serving one
+    /// with `None`.
+    fn configure_website(
+        &self,
+        website: Option<&Website>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// A cache in front of an [`ObjectStore`] that must be told when objects change.
+pub trait CacheInvalidator {
+    /// Invalidate the given paths. `caller_reference` identifies the invalidation.
+    fn invalidate(
+        &self,
+        paths: Vec<String>,
+        caller_reference: &str,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// No cache at all, when `None`.
+impl<C: CacheInvalidator + Sync> CacheInvalidator for Option<C> {
+    async fn invalidate(&self, paths: Vec<String>, caller_reference: &str) -> Result<(), Error> {
+        match self {
+            Some(invalidator) => invalidator.invalidate(paths, caller_reference).await,
+            None => {
+                log::info!("there is no cache, skipping invalidation");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The most keys s3 deletes in one request.
+const MAX_DELETE_BATCH: usize = 1000;
+
+/// s3 rejects multipart upload parts smaller than this, other than the last one.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Files at least this large are uploaded in parts by default.
+const DEFAULT_MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// The default size of multipart upload parts.
+const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Parts uploaded at once by default.
+const DEFAULT_PART_CONCURRENCY: usize = 4;
+
+/// The s3 user metadata key content hashes are stored under.
+const CONTENT_HASH_METADATA: &str = "content-hash";
+
+/// Returns the s3 user metadata for an object with the given content hash.
+fn content_hash_metadata(
+    content_hash: Option<String>,
+) -> Option<std::collections::HashMap<String, String>> {
+    content_hash.map(|hash| [(CONTENT_HASH_METADATA.to_owned(), hash)].into())
+}
+
+/// Returns the `x-amz-tagging` header value of `tags`, like "k1=v1&k2=v2", or
+/// `None` if there are none.
+fn tagging(tags: &BTreeMap<String, String>) -> Option<String> {
+    (!tags.is_empty()).then(|| {
+        tags.iter()
+            .map(|(key, value)| {
+                format!(
+                    "{}={}",
+                    urlencoding::encode(key),
+                    urlencoding::encode(value)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    })
+}
+
+/// Returns whether s3 refused a conditional write because its precondition failed.
+fn is_precondition_failed<E: ProvideErrorMetadata, R>(
+    error: &aws_sdk_s3::error::SdkError<E, R>,
+) -> bool {
+    error
+        .as_service_error()
+        .and_then(|error| error.code())
+        .is_some_and(|code| code == "PreconditionFailed")
+}
+
+/// AWS requests in flight at once by default.
+pub(crate) const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Returns a semaphore allowing the default number of AWS requests in flight.
+fn default_in_flight() -> Arc<tokio::sync::Semaphore> {
+    Arc::new(tokio::sync::Semaphore::new(DEFAULT_CONCURRENCY))
+}
+
+/// Controls when and how [`S3Store`] uses multipart uploads.
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Multipart {
+    /// Files of at least this many bytes are uploaded in parts.
+    #[clap(long = "multipart-threshold", default_value_t = DEFAULT_MULTIPART_THRESHOLD)]
+    pub threshold: u64,
+
+    /// The size of each part of a multipart upload in bytes. Raised to 5 MiB if
+    /// it is smaller.
+    #[clap(long = "multipart-part-size", default_value_t = DEFAULT_PART_SIZE)]
+    pub part_size: u64,
+
+    /// The number of parts of a multipart upload sent at once.
+    #[clap(id = "multipart_concurrency", long = "multipart-concurrency", default_value_t = DEFAULT_PART_CONCURRENCY)]
+    pub concurrency: usize,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Multipart {
+            threshold: DEFAULT_MULTIPART_THRESHOLD,
+            part_size: DEFAULT_PART_SIZE,
+            concurrency: DEFAULT_PART_CONCURRENCY,
+        }
+    }
+}
+
+/// List every object in `bucket` whose key starts with `prefix`.
+///
+/// s3 returns at most 1000 objects per request, so this follows continuation
+/// tokens until the listing is complete. Anything that lists a bucket must go
+/// through here.
+pub async fn list_all_objects(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<aws_sdk_s3::types::Object>, Error> {
+    let mut objects = vec![];
+    let mut continuation_token = None;
+    loop {
+        let page = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token.take())
+            .send()
+            .await
+            .boxed()
+            .context(S3Snafu {
+                operation: "list",
+                key: prefix,
+            })?;
+        objects.extend(page.contents().iter().cloned());
+        match page.next_continuation_token() {
+            Some(token) if page.is_truncated().unwrap_or_default() => {
+                log::debug!("listed {} objects, continuing", objects.len());
+                continuation_token = Some(token.to_owned());
+            }
+            _ => break,
+        }
+    }
+    Ok(objects)
+}
+
+/// An [`ObjectStore`] backed by an s3 bucket.
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    multipart: Multipart,
+    in_flight: Arc<tokio::sync::Semaphore>,
+    storage_class: Option<aws_sdk_s3::types::StorageClass>,
+}
+
+impl S3Store {
+    pub fn new(config: &aws_config::SdkConfig, bucket: impl Into<String>) -> Self {
+        S3Store {
+            client: aws_sdk_s3::Client::new(config),
+            bucket: bucket.into(),
+            multipart: Multipart::default(),
+            in_flight: default_in_flight(),
+            storage_class: None,
+        }
+    }
+
+    /// Store objects in the given storage class, like "STANDARD_IA", instead of
+    /// the bucket's default.
+    pub fn with_storage_class(mut self, storage_class: Option<&str>) -> Self {
+        self.storage_class = storage_class.map(aws_sdk_s3::types::StorageClass::from);
+        self
+    }
+
+    /// Set when and how large files are uploaded in parts.
+    pub fn with_multipart(mut self, multipart: Multipart) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Limit requests with the given semaphore, which may be shared with a
+    /// [`CloudFrontInvalidator`] to bound the total.
+    pub fn with_in_flight(mut self, in_flight: Arc<tokio::sync::Semaphore>) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    /// Wait until another request may be sent.
+    async fn permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.in_flight.acquire().await.unwrap()
+    }
+
+    /// Create a store for the bucket of the given environment.
+    pub async fn from_config(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        aws: &AwsOptions,
+    ) -> Result<Self, Error> {
+        let bucket = aws.bucket(cfg, environment).context(NoBucketSnafu {
+            environment: environment.clone(),
+        })?;
+        Ok(
+            Self::new(&crate::aws_sdk_config(cfg, environment, aws).await, bucket)
+                .with_storage_class(aws.storage_class(cfg, environment).as_deref()),
+        )
+    }
+
+    /// Upload a large file in parts, aborting the upload if any part fails so that
+    /// no incomplete upload is left behind.
+    async fn put_multipart(&self, object: PutObject, size: u64) -> Result<(), Error> {
+        let PutObject {
+            key,
+            path,
+            content_type,
+            cache_control,
+            content_encoding,
+            tags,
+            content_hash,
+            create_only,
+        } = object;
+        let permit = self.permit().await;
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&key)
+            .content_type(content_type)
+            .set_cache_control(cache_control)
+            .set_content_encoding(content_encoding)
+            .set_tagging(tagging(&tags))
+            .set_metadata(content_hash_metadata(content_hash))
+            .set_storage_class(self.storage_class.clone())
+            .send()
+            .await
+            .boxed()
+            .context(S3Snafu {
+                operation: "multipart upload",
+                key: &key,
+            })?;
+        drop(permit);
+        let upload_id = upload.upload_id().unwrap_or_default();
+
+        let result = match self.upload_parts(&key, &path, upload_id, size).await {
+            Ok(parts) => {
+                let _permit = self.permit().await;
+                let completed = self
+                    .client
+                    .complete_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&key)
+                    .upload_id(upload_id)
+                    .set_if_none_match(create_only.then(|| "*".to_owned()))
+                    .multipart_upload(
+                        aws_sdk_s3::types::CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await;
+                match completed {
+                    Err(e) if is_precondition_failed(&e) => AlreadyExistsSnafu { key: &key }.fail(),
+                    completed => completed.map(|_| ()).boxed().context(S3Snafu {
+                        operation: "multipart upload",
+                        key: &key,
+                    }),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() {
+            log::warn!("aborting multipart upload of '{key}'");
+            let _permit = self.permit().await;
+            if let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                log::error!(
+                    "could not abort multipart upload '{upload_id}' of '{key}': {}",
+                    aws_sdk_s3::error::DisplayErrorContext(e)
+                );
+            }
+        }
+        result
+    }
+
+    /// Upload the parts of `path`, returning them in order.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        path: &std::path::Path,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Vec<aws_sdk_s3::types::CompletedPart>, Error> {
+        let part_size = self.multipart.part_size.max(MIN_PART_SIZE);
+        let part_count = size.div_ceil(part_size);
+        log::info!("uploading '{key}' in {part_count} parts");
+        futures_util::stream::iter((0..part_count).map(|index| async move {
+            let offset = index * part_size;
+            let part_number = index as i32 + 1;
+            let body = aws_sdk_s3::primitives::ByteStream::read_from()
+                .path(path)
+                .offset(offset)
+                .length(aws_sdk_s3::primitives::Length::Exact(
+                    part_size.min(size - offset),
+                ))
+                .build()
+                .await
+                .boxed()
+                .context(S3Snafu {
+                    operation: "read",
+                    key,
+                })?;
+            let _permit = self.permit().await;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(body)
+                .send()
+                .await
+                .boxed()
+                .context(S3Snafu {
+                    operation: "part upload",
+                    key,
+                })?;
+            log::debug!("uploaded part {part_number}/{part_count} of '{key}'");
+            Ok(aws_sdk_s3::types::CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag)
+                .build())
+        }))
+        .buffered(self.multipart.concurrency.max(1))
+        .try_collect()
+        .await
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn name(&self) -> &str {
+        &self.bucket
+    }
+
+    async fn put_object(&self, object: PutObject) -> Result<(), Error> {
+        let size = std::fs::metadata(&object.path)
+            .boxed()
+            .context(S3Snafu {
+                operation: "read",
+                key: &object.key,
+            })?
+            .len();
+        if size >= self.multipart.threshold {
+            return self.put_multipart(object, size).await;
+        }
+
+        let PutObject {
+            key,
+            path,
+            content_type,
+            cache_control,
+            content_encoding,
+            tags,
+            content_hash,
+            create_only,
+        } = object;
+        let body = aws_sdk_s3::primitives::ByteStream::from_path(&path)
+            .await
+            .boxed()
+            .context(S3Snafu {
+                operation: "read",
+                key: &key,
+            })?;
+        let _permit = self.permit().await;
+        let put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .content_type(content_type)
+            .set_cache_control(cache_control)
+            .set_content_encoding(content_encoding)
+            .set_tagging(tagging(&tags))
+            .set_metadata(content_hash_metadata(content_hash))
+            .set_storage_class(self.storage_class.clone())
+            .set_if_none_match(create_only.then(|| "*".to_owned()))
+            .body(body)
+            .send()
+            .await;
+        if matches!(&put, Err(e) if is_precondition_failed(e)) {
+            return AlreadyExistsSnafu { key }.fail();
+        }
+        put.boxed().context(S3Snafu {
+            operation: "upload",
+            key: &key,
+        })?;
+        Ok(())
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<ObjectHead>, Error> {
+        let _permit = self.permit().await;
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(head) => Ok(Some(ObjectHead {
+                content_length: head.content_length().unwrap_or_default().max(0) as u64,
+                content_type: head.content_type().map(str::to_owned),
+                cache_control: head.cache_control().map(str::to_owned),
+                content_hash: head
+                    .metadata()
+                    .and_then(|metadata| metadata.get(CONTENT_HASH_METADATA))
+                    .cloned(),
+            })),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(e).boxed().context(S3Snafu {
+                operation: "head",
+                key,
+            }),
+        }
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        let _permit = self.permit().await;
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .boxed()
+            .context(S3Snafu {
+                operation: "delete",
+                key,
+            })?;
+        Ok(())
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let objects = list_all_objects(&self.client, &self.bucket, prefix).await?;
+        Ok(objects
+            .into_iter()
+            .filter_map(|object| object.key)
+            .collect())
+    }
+
+    async fn delete_objects(&self, keys: Vec<String>) -> Result<(), Error> {
+        for batch in keys.chunks(MAX_DELETE_BATCH) {
+            let objects = batch
+                .iter()
+                .map(|key| {
+                    aws_sdk_s3::types::ObjectIdentifier::builder()
+                        .key(key)
+                        .build()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let _permit = self.permit().await;
+            let output = self
+                .client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(
+                    aws_sdk_s3::types::Delete::builder()
+                        .set_objects(Some(objects))
+                        .quiet(true)
+                        .build()
+                        .unwrap(),
+                )
+                .send()
+                .await
+                .boxed()
+                .context(S3Snafu {
+                    operation: "batch delete",
+                    key: batch.first().cloned().unwrap_or_default(),
+                })?;
+            if let Some(error) = output.errors().first() {
+                for error in output.errors() {
+                    log::error!(
+                        "could not delete '{}': {}",
+                        error.key().unwrap_or_default(),
+                        error.message().unwrap_or_default()
+                    );
+                }
+                let message = error.message().unwrap_or_default().to_owned();
+                return Err(message.into()).context(S3Snafu {
+                    operation: "batch delete",
+                    key: error.key().unwrap_or_default(),
+                });
+            }
+            log::info!("deleted {} objects from '{}'", batch.len(), self.bucket);
+        }
+        Ok(())
+    }
+
+    async fn website(&self) -> Result<Option<Website>, Error> {
+        let _permit = self.permit().await;
+        match self
+            .client
+            .get_bucket_website()
+            .bucket(&self.bucket)
+            .send()
+            .await
+        {
+            Ok(website) => Ok(Some(Website {
+                index_document: website
+                    .index_document()
+                    .map(|index| index.suffix().to_owned()),
+                error_document: website.error_document().map(|error| error.key().to_owned()),
+                redirect_all_requests_to: website.redirect_all_requests_to().map(|redirect| {
+                    RedirectAll {
+                        host_name: redirect.host_name().to_owned(),
+                        protocol: redirect.protocol().map(|p| p.as_str().to_owned()),
+                    }
+                }),
+                routing_rules: website
+                    .routing_rules()
+                    .iter()
+                    .map(|rule| {
+                        let condition = rule.condition();
+                        let redirect = rule.redirect();
+                        RoutingRule {
+                            http_error_code_returned_equals: condition
+                                .and_then(|c| c.http_error_code_returned_equals())
+                                .map(str::to_owned),
+                            key_prefix_equals: condition
+                                .and_then(|c| c.key_prefix_equals())
+                                .map(str::to_owned),
+                            host_name: redirect.and_then(|r| r.host_name()).map(str::to_owned),
+                            http_redirect_code: redirect
+                                .and_then(|r| r.http_redirect_code())
+                                .map(str::to_owned),
+                            protocol: redirect
+                                .and_then(|r| r.protocol())
+                                .map(|p| p.as_str().to_owned()),
+                            replace_key_prefix_with: redirect
+                                .and_then(|r| r.replace_key_prefix_with())
+                                .map(str::to_owned),
+                            replace_key_with: redirect
+                                .and_then(|r| r.replace_key_with())
+                                .map(str::to_owned),
+                        }
+                    })
+                    .collect(),
+            })),
+            Err(e)
+                if e.as_service_error().and_then(|e| e.code())
+                    == Some("NoSuchWebsiteConfiguration") =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).boxed().context(S3Snafu {
+                operation: "website configuration",
+                key: "",
+            }),
+        }
+    }
+
+    async fn configure_website(&self, website: Option<&Website>) -> Result<(), Error> {
+        let Some(website) = website else {
+            let _permit = self.permit().await;
+            self.client
+                .delete_bucket_website()
+                .bucket(&self.bucket)
+                .send()
+                .await
+                .boxed()
+                .context(S3Snafu {
+                    operation: "website configuration",
+                    key: "",
+                })?;
+            return Ok(());
+        };
+        use aws_sdk_s3::types::Protocol;
+        let routing_rules = website
+            .routing_rules
+            .iter()
+            .map(|rule| {
+                let condition = (rule.http_error_code_returned_equals.is_some()
+                    || rule.key_prefix_equals.is_some())
+                .then(|| {
+                    aws_sdk_s3::types::Condition::builder()
+                        .set_http_error_code_returned_equals(
+                            rule.http_error_code_returned_equals.clone(),
+                        )
+                        .set_key_prefix_equals(rule.key_prefix_equals.clone())
+                        .build()
+                });
+                aws_sdk_s3::types::RoutingRule::builder()
+                    .set_condition(condition)
+                    .redirect(
+                        aws_sdk_s3::types::Redirect::builder()
+                            .set_host_name(rule.host_name.clone())
+                            .set_http_redirect_code(rule.http_redirect_code.clone())
+                            .set_protocol(rule.protocol.as_deref().map(Protocol::from))
+                            .set_replace_key_prefix_with(rule.replace_key_prefix_with.clone())
+                            .set_replace_key_with(rule.replace_key_with.clone())
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let website = aws_sdk_s3::types::WebsiteConfiguration::builder()
+            .set_index_document(website.index_document.as_ref().map(|index_document| {
+                aws_sdk_s3::types::IndexDocument::builder()
+                    .suffix(index_document)
+                    .build()
+                    .unwrap()
+            }))
+            .set_error_document(website.error_document.as_ref().map(|error_document| {
+                aws_sdk_s3::types::ErrorDocument::builder()
+                    .key(error_document)
+                    .build()
+                    .unwrap()
+            }))
+            .set_redirect_all_requests_to(website.redirect_all_requests_to.as_ref().map(
+                |redirect| {
+                    aws_sdk_s3::types::RedirectAllRequestsTo::builder()
+                        .host_name(&redirect.host_name)
+                        .set_protocol(redirect.protocol.as_deref().map(Protocol::from))
+                        .build()
+                        .unwrap()
+                },
+            ))
+            .set_routing_rules((!routing_rules.is_empty()).then_some(routing_rules))
+            .build();
+        let _permit = self.permit().await;
+        self.client
+            .put_bucket_website()
+            .bucket(&self.bucket)
+            .website_configuration(website)
+            .send()
+            .await
+            .boxed()
+            .context(S3Snafu {
+                operation: "website configuration",
+                key: "",
+            })?;
+        Ok(())
+    }
+}
+
+/// A [`CacheInvalidator`] for one or more cloudfront distributions, like one per
+/// domain in front of the same bucket. Each invalidation is created on all of them.
+pub struct CloudFrontInvalidator {
+    client: aws_sdk_cloudfront::Client,
+    distribution_ids: Vec<String>,
+    in_flight: Arc<tokio::sync::Semaphore>,
+}
+
+impl CloudFrontInvalidator {
+    pub fn new(
+        config: &aws_config::SdkConfig,
+        distribution_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        CloudFrontInvalidator {
+            client: aws_sdk_cloudfront::Client::new(config),
+            distribution_ids: distribution_ids.into_iter().map(Into::into).collect(),
+            in_flight: default_in_flight(),
+        }
+    }
+
+    /// Limit requests with the given semaphore, which may be shared with an
+    /// [`S3Store`] to bound the total.
+    pub fn with_in_flight(mut self, in_flight: Arc<tokio::sync::Semaphore>) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    /// Create an invalidator for the distributions of the given environment.
+    pub async fn from_config(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        aws: &AwsOptions,
+    ) -> Result<Self, Error> {
+        let distribution_ids = aws.distributions(cfg, environment);
+        snafu::ensure!(
+            !distribution_ids.is_empty(),
+            NoDistributionSnafu {
+                environment: environment.clone(),
+            }
+        );
+        Ok(Self::new(
+            &crate::aws_sdk_config(cfg, environment, aws).await,
+            distribution_ids,
+        ))
+    }
+
+    /// Create an invalidator for the distributions of the given environment, or
+    /// return `None` if it has none, like a site served straight from s3.
+    pub async fn maybe_from_config(
+        cfg: &SiteConfig,
+        environment: &Environment,
+        aws: &AwsOptions,
+    ) -> Result<Option<Self>, Error> {
+        if aws.distributions(cfg, environment).is_empty() {
+            log::info!(
+                "'{environment}' has no cloudfront distribution, nothing will be invalidated"
+            );
+            return Ok(None);
+        }
+        Self::from_config(cfg, environment, aws).await.map(Some)
+    }
+}
+
+impl CacheInvalidator for CloudFrontInvalidator {
+    async fn invalidate(&self, paths: Vec<String>, caller_reference: &str) -> Result<(), Error> {
+        let batch = aws_sdk_cloudfront::types::InvalidationBatch::builder()
+            .paths(
+                aws_sdk_cloudfront::types::Paths::builder()
+                    .quantity(paths.len() as i32)
+                    .set_items(Some(paths))
+                    .build()
+                    .unwrap(),
+            )
+            .caller_reference(caller_reference)
+            .build()
+            .unwrap();
+        futures_util::future::join_all(self.distribution_ids.iter().map(|distribution_id| {
+            let batch = batch.clone();
+            async move {
+                let _permit = self.in_flight.acquire().await.unwrap();
+                let invalidation = self
+                    .client
+                    .create_invalidation()
+                    .distribution_id(distribution_id)
+                    .invalidation_batch(batch)
+                    .send()
+                    .await
+                    .boxed()
+                    .context(CloudFrontSnafu)?;
+                log::info!("created invalidation: {invalidation:#?}");
+                Ok(())
+            }
+        }))
+        .await
+        .into_iter()
+        .collect()
+    }
+}
+
+/// An object held by a [`MemoryStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryObject {
+    pub content_type: String,
+    pub cache_control: Option<String>,
+    pub content_encoding: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    pub content_hash: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// An in-memory [`ObjectStore`], for testing deploys without AWS.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, MemoryObject>>,
+    website: Mutex<Option<Website>>,
+}
+
+impl MemoryStore {
+    /// The stored objects, keyed by key.
+    pub fn objects(&self) -> BTreeMap<String, MemoryObject> {
+        self.objects.lock().unwrap().clone()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    async fn put_object(&self, object: PutObject) -> Result<(), Error> {
+        let body = std::fs::read(&object.path).boxed().context(S3Snafu {
+            operation: "read",
+            key: &object.key,
+        })?;
+        let mut objects = self.objects.lock().unwrap();
+        snafu::ensure!(
+            !object.create_only || !objects.contains_key(&object.key),
+            AlreadyExistsSnafu { key: &object.key }
+        );
+        let object_data = MemoryObject {
+            content_type: object.content_type,
+            cache_control: object.cache_control,
+            content_encoding: object.content_encoding,
+            tags: object.tags,
+            content_hash: object.content_hash,
+            body,
+        };
+        objects.insert(object.key, object_data);
+        Ok(())
+    }
+
+    async fn head_object(&self, key: &str) -> Result<Option<ObjectHead>, Error> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects.get(key).map(|object| ObjectHead {
+            content_length: object.body.len() as u64,
+            content_type: Some(object.content_type.clone()),
+            cache_control: object.cache_control.clone(),
+            content_hash: object.content_hash.clone(),
+        }))
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), Error> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_objects(&self, keys: Vec<String>) -> Result<(), Error> {
+        let mut objects = self.objects.lock().unwrap();
+        for key in keys {
+            objects.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn website(&self) -> Result<Option<Website>, Error> {
+        Ok(self.website.lock().unwrap().clone())
+    }
+
+    async fn configure_website(&self, website: Option<&Website>) -> Result<(), Error> {
+        *self.website.lock().unwrap() = website.cloned();
+        Ok(())
+    }
+}
+
+/// An in-memory [`CacheInvalidator`] that records invalidations, for testing.
+#[derive(Debug, Default)]
+pub struct MemoryInvalidator {
+    invalidations: Mutex<Vec<Vec<String>>>,
+}
+
+impl MemoryInvalidator {
+    /// The paths of each invalidation, in order.
+    pub fn invalidations(&self) -> Vec<Vec<String>> {
+        self.invalidations.lock().unwrap().clone()
+    }
+}
+
+impl CacheInvalidator for MemoryInvalidator {
+    async fn invalidate(&self, paths: Vec<String>, _caller_reference: &str) -> Result<(), Error> {
+        self.invalidations.lock().unwrap().push(paths);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use aws_sdk_s3::primitives::SdkBody;
+    use aws_smithy_runtime::client::http::test_util::{ReplayEvent, StaticReplayClient};
+
+    use super::*;
+
+    fn page(keys: &[&str], next: Option<&str>) -> ReplayEvent {
+        let contents = keys
+            .iter()
+            .map(|key| format!("<Contents><Key>{key}</Key></Contents>"))
+            .collect::<String>();
+        let next = next
+            .map(|token| format!("<NextContinuationToken>{token}</NextContinuationToken>"))
+            .unwrap_or_default();
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+            <Name>bucket</Name><Prefix>blog/</Prefix><KeyCount>{}</KeyCount>
+            <IsTruncated>{}</IsTruncated>{contents}{next}</ListBucketResult>"#,
+            keys.len(),
+            !next.is_empty(),
+        );
+        ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://bucket.s3.us-west-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(SdkBody::from(body))
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn list_all_objects_pages() {
+        let http_client = StaticReplayClient::new(vec![
+            page(&["blog/a.html", "blog/b.html"], Some("token-1")),
+            page(&["blog/c.html"], Some("token-2")),
+            page(&["blog/d.html"], None),
+        ]);
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-west-1"))
+            .credentials_provider(aws_sdk_s3::config::Credentials::new(
+                "key", "secret", None, None, "test",
+            ))
+            .http_client(http_client.clone())
+            .build();
+        let client = aws_sdk_s3::Client::from_conf(config);
+
+        let keys = list_all_objects(&client, "bucket", "blog/")
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|object| object.key)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["blog/a.html", "blog/b.html", "blog/c.html", "blog/d.html"],
+            keys
+        );
+
+        let uris = http_client
+            .actual_requests()
+            .map(|request| request.uri().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(3, uris.len());
+        assert!(!uris[0].contains("continuation-token"));
+        assert!(uris[1].contains("continuation-token=token-1"));
+        assert!(uris[2].contains("continuation-token=token-2"));
+    }
+
+    #[test]
+    fn taggings() {
+        assert_eq!(None, tagging(&BTreeMap::new()));
+        let tags = BTreeMap::from([
+            ("managed-by".to_owned(), "pusha".to_owned()),
+            ("team".to_owned(), "web & docs".to_owned()),
+        ]);
+        assert_eq!(
+            Some("managed-by=pusha&team=web%20%26%20docs".to_owned()),
+            tagging(&tags)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidates_every_distribution() {
+        let invalidation = || {
+            ReplayEvent::new(
+                http::Request::builder()
+                    .uri("https://cloudfront.amazonaws.com/")
+                    .body(SdkBody::empty())
+                    .unwrap(),
+                http::Response::builder()
+                    .status(201)
+                    .body(SdkBody::from(
+                        r#"<?xml version="1.0" encoding="UTF-8"?>
+                        <Invalidation xmlns="http://cloudfront.amazonaws.com/doc/2020-05-31/">
+                        <Id>I1</Id><Status>InProgress</Status></Invalidation>"#,
+                    ))
+                    .unwrap(),
+            )
+        };
+        let http_client = StaticReplayClient::new(vec![invalidation(), invalidation()]);
+        let config = aws_config::SdkConfig::builder()
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("us-east-1"))
+            .credentials_provider(aws_sdk_s3::config::SharedCredentialsProvider::new(
+                aws_sdk_s3::config::Credentials::new("key", "secret", None, None, "test"),
+            ))
+            .http_client(http_client.clone())
+            .build();
+        let invalidator = CloudFrontInvalidator::new(&config, ["E1", "E2"]);
+        invalidator
+            .invalidate(vec!["/*".to_owned()], "reference")
+            .await
+            .unwrap();
+
+        let mut uris = http_client
+            .actual_requests()
+            .map(|request| request.uri().to_owned())
+            .collect::<Vec<_>>();
+        uris.sort();
+        assert_eq!(2, uris.len());
+        assert!(uris[0].contains("/distribution/E1/invalidation"));
+        assert!(uris[1].contains("/distribution/E2/invalidation"));
+    }
+}